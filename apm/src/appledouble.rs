@@ -0,0 +1,113 @@
+//! AppleSingle and AppleDouble files, which keep resource forks and Finder information of Mac
+//! OS files on other filesystems
+
+use crate::hfs::File;
+
+pub const APPLE_SINGLE_MAGIC: u32 = 0x0005_1600;
+pub const APPLE_DOUBLE_MAGIC: u32 = 0x0005_1607;
+
+const ENTRY_DATA: u32 = 1;
+const ENTRY_RSRC: u32 = 2;
+const ENTRY_NAME: u32 = 3;
+const ENTRY_DATES: u32 = 8;
+const ENTRY_FINDER_INFO: u32 = 9;
+
+/// Seconds between 1904, the Mac OS epoch, and 2000, the AppleSingle epoch
+const EPOCH_OFFSET: i64 = 3_029_529_600;
+
+fn be16(d: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(d.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn be32(d: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(d.get(off..off + 4)?.try_into().ok()?))
+}
+
+/// Decodes an AppleSingle or AppleDouble file. The name is only set if the file records one,
+/// the data fork is empty for AppleDouble files.
+pub fn decode(bytes: &[u8]) -> Option<File> {
+    let magic = be32(bytes, 0)?;
+    if magic != APPLE_SINGLE_MAGIC && magic != APPLE_DOUBLE_MAGIC {
+        return None;
+    }
+    let count = be16(bytes, 24)? as usize;
+    let mut ret = File::default();
+    for i in 0..count {
+        let entry = 26 + i*12;
+        let id = be32(bytes, entry)?;
+        let offset = be32(bytes, entry + 4)? as usize;
+        let len = be32(bytes, entry + 8)? as usize;
+        let data = bytes.get(offset..)?.get(..len)?;
+        match id {
+            ENTRY_DATA => ret.data = data.to_vec(),
+            ENTRY_RSRC => ret.rsrc = data.to_vec(),
+            ENTRY_NAME => ret.name = crate::macroman::decode(data),
+            ENTRY_DATES if len >= 8 => {
+                let date = |off| (be32(data, off).unwrap() as i32 as i64 + EPOCH_OFFSET).clamp(0, u32::MAX as i64) as u32;
+                ret.create_date = date(0);
+                ret.modify_date = date(4);
+            },
+            ENTRY_FINDER_INFO => {
+                let len = len.min(32);
+                ret.finder_info[..len].copy_from_slice(&data[..len]);
+            },
+            _ => (),
+        }
+    }
+    Some(ret)
+}
+
+fn encode(magic: u32, entries: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    ret.extend_from_slice(&magic.to_be_bytes());
+    ret.extend_from_slice(&0x0002_0000u32.to_be_bytes());
+    ret.extend_from_slice(&[0; 16]);
+    ret.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+    let mut offset = 26 + entries.len()*12;
+    for (id, data) in entries {
+        for v in [*id, offset as u32, data.len() as u32] {
+            ret.extend_from_slice(&v.to_be_bytes());
+        }
+        offset += data.len();
+    }
+    for (_, data) in entries {
+        ret.extend_from_slice(data);
+    }
+    ret
+}
+
+/// Creation, modification, backup and access dates
+fn dates(file: &File) -> Vec<u8> {
+    let date = |d: u32| (d as i64 - EPOCH_OFFSET).clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+    [date(file.create_date), date(file.modify_date), i32::MIN, date(file.modify_date)]
+        .iter()
+        .flat_map(|d| d.to_be_bytes())
+        .collect()
+}
+
+/// Encodes a file with both forks as AppleSingle
+pub fn encode_single(file: &File) -> Vec<u8> {
+    let name: Vec<u8> = file.name.chars()
+        .map(|c| crate::macroman::encode_char(c).unwrap_or(b'?'))
+        .collect();
+    let dates = dates(file);
+    encode(APPLE_SINGLE_MAGIC, &[
+        (ENTRY_NAME, &name),
+        (ENTRY_DATES, &dates),
+        (ENTRY_FINDER_INFO, &file.finder_info),
+        (ENTRY_RSRC, &file.rsrc),
+        (ENTRY_DATA, &file.data),
+    ])
+}
+
+/// Encodes everything but the data fork of a file as AppleDouble, to be stored in a `._` file
+/// next to the data fork
+pub fn encode_double(file: &File) -> Vec<u8> {
+    let dates = dates(file);
+    // Mac OS X expects Finder information first and the resource fork last
+    encode(APPLE_DOUBLE_MAGIC, &[
+        (ENTRY_DATES, &dates),
+        (ENTRY_FINDER_INFO, &file.finder_info),
+        (ENTRY_RSRC, &file.rsrc),
+    ])
+}
@@ -0,0 +1,354 @@
+//! Reading of the B*-trees used by HFS and HFS+ for their catalog and extents overflow files
+
+use std::cmp::Ordering;
+use deku::prelude::*;
+
+pub(crate) const KIND_INDEX: i8 = 0;
+pub(crate) const KIND_HEADER: i8 = 1;
+pub(crate) const KIND_LEAF: i8 = -1;
+pub(crate) const KIND_MAP: i8 = 2;
+
+/// Size of the nodes of trees built by `build`
+const NODE_SIZE: usize = 512;
+/// Nodes described by the map record of the header node
+const HEADER_MAP_NODES: u32 = (NODE_SIZE as u32 - 14 - 106 - 128 - 2*4)*8;
+/// Nodes described by the record of each map node
+const MAP_NODE_NODES: u32 = (NODE_SIZE as u32 - 14 - 2*2)*8;
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct NodeDescriptor {
+    /// Next node of the same kind and height
+    pub(crate) flink: u32,
+    /// Previous node of the same kind and height
+    pub(crate) blink: u32,
+    pub(crate) kind: i8,
+    /// Level of the node, leaves are at 1
+    pub(crate) height: u8,
+    pub(crate) num_records: u16,
+    pub(crate) reserved: u16,
+}
+
+/// First record of the header node
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct HeaderRecord {
+    pub(crate) depth: u16,
+    pub(crate) root: u32,
+    pub(crate) leaf_records: u32,
+    pub(crate) first_leaf: u32,
+    pub(crate) last_leaf: u32,
+    pub(crate) node_size: u16,
+    pub(crate) max_key_len: u16,
+    pub(crate) total_nodes: u32,
+    pub(crate) free_nodes: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum KeyLength {
+    /// One byte of key length, as used by HFS
+    Byte,
+    /// Two bytes of key length, as used by HFS+
+    Word,
+}
+
+/// Splits a record into its key, without the length prefix, and the data following it
+pub(crate) fn split_record(record: &[u8], key_len: KeyLength) -> Option<(&[u8], &[u8])> {
+    let (len, prefix) = match key_len {
+        KeyLength::Byte => (*record.first()? as usize, 1),
+        KeyLength::Word => (u16::from_be_bytes([*record.first()?, *record.get(1)?]) as usize, 2),
+    };
+    let key = record.get(prefix..prefix + len)?;
+    // Data always starts on an even offset
+    let data_start = (prefix + len + 1) & !1;
+    Some((key, record.get(data_start..)?))
+}
+
+/// A B*-tree stored in memory
+pub(crate) struct BTree {
+    data: Vec<u8>,
+    header: HeaderRecord,
+    key_len: KeyLength,
+}
+
+impl BTree {
+    pub(crate) fn new(data: Vec<u8>, key_len: KeyLength) -> Option<Self> {
+        let (_, desc) = NodeDescriptor::from_bytes((data.get(..14)?, 0)).ok()?;
+        if desc.kind != KIND_HEADER {
+            return None;
+        }
+        let (_, header) = HeaderRecord::from_bytes((data.get(14..14 + 30)?, 0)).ok()?;
+        if header.node_size < 512 || !header.node_size.is_power_of_two() {
+            return None;
+        }
+        Some(Self { data, header, key_len })
+    }
+    /// How keys of an HFSX catalog are compared, 0 for other trees
+    pub(crate) fn key_compare_type(&self) -> u8 {
+        self.data.get(14 + 37).copied().unwrap_or(0)
+    }
+    fn node(&self, num: u32) -> Option<(NodeDescriptor, &[u8])> {
+        let size = self.header.node_size as usize;
+        let node = self.data.get(num as usize * size..)?.get(..size)?;
+        let (_, desc) = NodeDescriptor::from_bytes((node, 0)).ok()?;
+        Some((desc, node))
+    }
+    /// Records stored in a node, in order
+    fn records<'a>(&self, desc: &NodeDescriptor, node: &'a [u8]) -> Vec<&'a [u8]> {
+        let size = node.len();
+        let offset = |i: usize| -> Option<usize> {
+            let pos = size.checked_sub(2*(i + 1))?;
+            Some(u16::from_be_bytes([node[pos], node[pos + 1]]) as usize)
+        };
+        (0..desc.num_records as usize)
+            .map_while(|i| node.get(offset(i)?..offset(i + 1)?))
+            .collect()
+    }
+    /// Finds the leaf node that would hold the first record whose key is not less than the
+    /// target. `cmp` compares a key with the target.
+    pub(crate) fn find_leaf(&self, cmp: impl Fn(&[u8]) -> Ordering) -> Option<u32> {
+        let mut num = self.header.root;
+        for _ in 0..=self.header.depth {
+            let (desc, node) = self.node(num)?;
+            match desc.kind {
+                KIND_LEAF => return Some(num),
+                KIND_INDEX => (),
+                _ => return None,
+            }
+            let records = self.records(&desc, node);
+            let mut child = None;
+            for record in records.iter() {
+                let (key, data) = split_record(record, self.key_len)?;
+                if child.is_some() && cmp(key) != Ordering::Less {
+                    break;
+                }
+                child = Some(u32::from_be_bytes(data.get(..4)?.try_into().ok()?));
+            }
+            num = child?;
+        }
+        None
+    }
+    /// Iterates over `(key, data)` of leaf records, starting at the given leaf node
+    pub(crate) fn leaf_records_from(&self, first: u32) -> impl Iterator<Item = (&[u8], &[u8])> {
+        let mut num = first;
+        let mut remaining = self.header.total_nodes;
+        std::iter::from_fn(move || {
+            if num == 0 || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let (desc, node) = self.node(num)?;
+            if desc.kind != KIND_LEAF {
+                return None;
+            }
+            num = desc.flink;
+            Some(self.records(&desc, node))
+        })
+        .flatten()
+        .filter_map(|record| split_record(record, self.key_len))
+    }
+    /// Iterates over `(key, data)` of all leaf records
+    pub(crate) fn leaf_records(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.leaf_records_from(self.header.first_leaf)
+    }
+}
+
+/// Writes a node holding `records` at the start of `node`
+fn write_node(node: &mut [u8], desc: &NodeDescriptor, records: &[&[u8]]) {
+    let size = node.len();
+    node.fill(0);
+    node[..14].copy_from_slice(&desc.to_bytes().unwrap());
+    let mut offset = 14;
+    for (i, record) in records.iter().chain(std::iter::once(&&[][..])).enumerate() {
+        node[offset..][..record.len()].copy_from_slice(record);
+        node[size - 2*(i + 1)..][..2].copy_from_slice(&(offset as u16).to_be_bytes());
+        offset += record.len();
+    }
+}
+
+/// Number of map nodes needed next to the header node by a tree of `total_nodes` nodes
+fn map_nodes(total_nodes: u32) -> u32 {
+    total_nodes.saturating_sub(HEADER_MAP_NODES).div_ceil(MAP_NODE_NODES)
+}
+
+/// Groups leaf records sorted by key into nodes, and those into index nodes up to the root.
+/// Returns the levels from the leaves up, each a list of nodes with their records, or `None` if
+/// a record does not fit in a node.
+fn levels(records: &[Vec<u8>], index_record: impl Fn(&[u8]) -> Vec<u8>) -> Option<Vec<Vec<Vec<Vec<u8>>>>> {
+    // Groups records into nodes, returning index ranges of each node
+    let pack = |lens: &[usize]| -> Option<Vec<(usize, usize)>> {
+        let mut ret = Vec::new();
+        let mut start = 0;
+        let mut used = 14 + 2;
+        for (i, len) in lens.iter().enumerate() {
+            if 14 + 2 + len + 2 > NODE_SIZE {
+                return None;
+            }
+            if used + len + 2 > NODE_SIZE {
+                ret.push((start, i));
+                start = i;
+                used = 14 + 2;
+            }
+            used += len + 2;
+        }
+        if start < lens.len() {
+            ret.push((start, lens.len()));
+        }
+        Some(ret)
+    };
+
+    let mut levels: Vec<Vec<Vec<Vec<u8>>>> = Vec::new();
+    let mut cur: Vec<Vec<u8>> = records.to_vec();
+    while !cur.is_empty() {
+        let lens: Vec<usize> = cur.iter().map(Vec::len).collect();
+        let nodes: Vec<Vec<Vec<u8>>> = pack(&lens)?
+            .into_iter()
+            .map(|(start, end)| cur[start..end].to_vec())
+            .collect();
+        let done = nodes.len() == 1;
+        // Pointers are filled in once node numbers are known
+        cur = nodes.iter()
+            .map(|n| {
+                let mut record = index_record(&n[0]);
+                record.extend_from_slice(&[0; 4]);
+                record
+            })
+            .collect();
+        levels.push(nodes);
+        if done {
+            break;
+        }
+    }
+    Some(levels)
+}
+
+/// Smallest number of 512-byte nodes a tree holding `records` can be built with, see `build`
+pub(crate) fn min_nodes(records: &[Vec<u8>], index_record: impl Fn(&[u8]) -> Vec<u8>) -> Option<u32> {
+    let used = 1 + levels(records, index_record)?.iter().map(Vec::len).sum::<usize>() as u32;
+    let mut total = used;
+    while used + map_nodes(total) > total {
+        total = used + map_nodes(total);
+    }
+    Some(total)
+}
+
+/// Builds a B*-tree of `total_nodes` nodes of 512 bytes from leaf records sorted by key.
+/// `index_record` turns the first leaf record of a node into an index record pointing at it,
+/// without the pointer. Trees of more nodes than the header node can describe get map nodes
+/// right after it.
+///
+/// Returns `None` if the records do not fit.
+pub(crate) fn build(records: &[Vec<u8>], index_record: impl Fn(&[u8]) -> Vec<u8>, max_key_len: u16, total_nodes: u32) -> Option<Vec<u8>> {
+    let levels = levels(records, index_record)?;
+    let map_count = map_nodes(total_nodes);
+
+    let used_nodes = 1 + map_count + levels.iter().map(Vec::len).sum::<usize>() as u32;
+    if used_nodes > total_nodes {
+        return None;
+    }
+    let mut ret = vec![0u8; total_nodes as usize*NODE_SIZE];
+
+    // Node numbers of each level, leaves come first after the map nodes
+    let mut next = 1 + map_count;
+    let numbers: Vec<Vec<u32>> = levels.iter()
+        .map(|nodes| nodes.iter().map(|_| { next += 1; next - 1 }).collect())
+        .collect();
+    for (height, nodes) in levels.iter().enumerate() {
+        let nums = &numbers[height];
+        for (i, node_records) in nodes.iter().enumerate() {
+            let mut node_records = node_records.clone();
+            if height > 0 {
+                // Each record points at the node of the level below it
+                let below = &numbers[height - 1];
+                let offset: usize = nodes[..i].iter().map(Vec::len).sum();
+                for (j, record) in node_records.iter_mut().enumerate() {
+                    let len = record.len();
+                    record[len - 4..].copy_from_slice(&below[offset + j].to_be_bytes());
+                }
+            }
+            let desc = NodeDescriptor {
+                flink: nums.get(i + 1).copied().unwrap_or(0),
+                blink: if i > 0 { nums[i - 1] } else { 0 },
+                kind: if height == 0 { KIND_LEAF } else { KIND_INDEX },
+                height: height as u8 + 1,
+                num_records: node_records.len() as u16,
+                reserved: 0,
+            };
+            let refs: Vec<&[u8]> = node_records.iter().map(Vec::as_slice).collect();
+            write_node(&mut ret[nums[i] as usize*NODE_SIZE..][..NODE_SIZE], &desc, &refs);
+        }
+    }
+
+    let leaves = numbers.first().map(Vec::as_slice).unwrap_or(&[]);
+    let header = HeaderRecord {
+        depth: levels.len() as u16,
+        root: numbers.last().map(|n| n[0]).unwrap_or(0),
+        leaf_records: records.len() as u32,
+        first_leaf: leaves.first().copied().unwrap_or(0),
+        last_leaf: leaves.last().copied().unwrap_or(0),
+        node_size: NODE_SIZE as u16,
+        max_key_len,
+        total_nodes,
+        free_nodes: total_nodes - used_nodes,
+    };
+    let mut header_record = header.to_bytes().ok()?;
+    header_record.resize(106, 0);
+    let user_record = [0u8; 128];
+    // The map record fills the rest of the header node, and continues in the map nodes
+    let mut map = vec![0u8; (HEADER_MAP_NODES + map_count*MAP_NODE_NODES) as usize/8];
+    for n in 0..used_nodes as usize {
+        map[n/8] |= 0x80 >> (n % 8);
+    }
+    let (header_map, mut rest) = map.split_at(HEADER_MAP_NODES as usize/8);
+    let flink = if map_count > 0 { 1 } else { 0 };
+    let desc = NodeDescriptor { flink, blink: 0, kind: KIND_HEADER, height: 0, num_records: 3, reserved: 0 };
+    write_node(&mut ret[..NODE_SIZE], &desc, &[&header_record, &user_record, header_map]);
+    for num in 1..=map_count {
+        let (record, next) = rest.split_at(MAP_NODE_NODES as usize/8);
+        rest = next;
+        let flink = if num < map_count { num + 1 } else { 0 };
+        let desc = NodeDescriptor { flink, blink: 0, kind: KIND_MAP, height: 0, num_records: 1, reserved: 0 };
+        write_node(&mut ret[num as usize*NODE_SIZE..][..NODE_SIZE], &desc, &[record]);
+    }
+    Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_trees_get_map_nodes() {
+        let records: Vec<Vec<u8>> = (0..20000u32)
+            .map(|i| {
+                let mut r = vec![5];
+                r.extend_from_slice(&i.to_be_bytes());
+                r.extend_from_slice(&[0; 40]);
+                r
+            })
+            .collect();
+        let index = |r: &[u8]| r[..6].to_vec();
+        let total = min_nodes(&records, index).unwrap() + 10_000;
+        let data = build(&records, index, 4, total).unwrap();
+        assert!(build(&records, index, 4, min_nodes(&records, index).unwrap() - 1).is_none());
+
+        // The header node points at a chain of map nodes covering every node
+        let mut map_count = 0;
+        let (mut desc, mut node) = NodeDescriptor::from_bytes((&data[..14], 0)).map(|(_, d)| (d, 0)).unwrap();
+        while desc.flink != 0 {
+            node = desc.flink as usize;
+            desc = NodeDescriptor::from_bytes((&data[node*NODE_SIZE..][..14], 0)).unwrap().1;
+            assert_eq!(desc.kind, KIND_MAP);
+            map_count += 1;
+        }
+        assert_eq!(map_count, map_nodes(total));
+        assert_eq!(node as u32, map_count);
+
+        let tree = BTree::new(data, KeyLength::Byte).unwrap();
+        assert_eq!(tree.leaf_records().count(), records.len());
+        let target = 12345u32.to_be_bytes();
+        let leaf = tree.find_leaf(|key| key[..4].cmp(&target)).unwrap();
+        let found = tree.leaf_records_from(leaf).find(|(k, _)| k[..4] >= target[..]).unwrap();
+        assert_eq!(found.0[..4], target);
+    }
+}
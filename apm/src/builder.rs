@@ -0,0 +1,376 @@
+//! Construction of whole disks from descriptions of their drivers and partitions
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{apple_checksum, holes_between, status, AllocPolicy, ApmError, ApmMap, DriverData, DriverType, PartitionEntry, Strategy};
+
+/// Contents of a new partition
+#[derive(Clone, Debug, Default)]
+pub enum Source {
+    /// Nothing but zeroes
+    #[default]
+    Zero,
+    Bytes(Vec<u8>),
+    /// A file, only read once the layout of the disk was checked
+    File(PathBuf),
+}
+
+impl Source {
+    fn len(&self) -> Result<u64, ApmError> {
+        Ok(match self {
+            Self::Zero => 0,
+            Self::Bytes(data) => data.len() as u64,
+            Self::File(path) => fs::metadata(path)?.len(),
+        })
+    }
+    fn read(self) -> Result<Vec<u8>, ApmError> {
+        Ok(match self {
+            Self::Zero => Vec::new(),
+            Self::Bytes(data) => data,
+            Self::File(path) => fs::read(path)?,
+        })
+    }
+}
+
+/// Boot code stored in a partition, as described by its map entry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BootInfo {
+    /// First block of the code, counted from the start of the partition
+    pub start: u32,
+    /// Size of the code in bytes, for drivers 0 stands for all of the data
+    pub size: u32,
+    pub load_address: u32,
+    pub entry: u32,
+    /// Checksum of the code, computed from the data if missing
+    pub checksum: Option<u32>,
+}
+
+/// A partition to be created by `ApmBuilder`
+#[derive(Clone, Debug)]
+pub struct PartitionSpec {
+    name: String,
+    ty: String,
+    size: Option<u32>,
+    start: Option<u32>,
+    align: u32,
+    status: u32,
+    proc_type: String,
+    boot: Option<BootInfo>,
+    source: Source,
+}
+
+impl PartitionSpec {
+    pub fn new(name: impl Into<String>, ty: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ty: ty.into(),
+            size: None,
+            start: None,
+            align: 1,
+            status: PartitionEntry::new().status(),
+            proc_type: String::new(),
+            boot: None,
+            source: Source::Zero,
+        }
+    }
+    /// A partition holding a driver of type `ty`, set up the way Apple's tools do
+    pub fn driver(ty: DriverType, source: Source) -> Self {
+        Self::new("Macintosh", ty.partition_type())
+            .with_status(status::DRIVER)
+            .with_proc_type(ty.proc_type())
+            .with_source(source)
+    }
+    /// Length in blocks, defaults to the size of the data
+    pub fn with_size(mut self, blocks: u32) -> Self {
+        self.size = Some(blocks);
+        self
+    }
+    /// First block, defaults to space picked by the strategy of the builder
+    pub fn with_start(mut self, block: u32) -> Self {
+        self.start = Some(block);
+        self
+    }
+    /// Aligns the first block to a multiple of `blocks`, 0 and 1 disable alignment
+    pub fn with_align(mut self, blocks: u32) -> Self {
+        self.align = blocks.max(1);
+        self
+    }
+    /// Status flags, see `status`
+    pub fn with_status(mut self, status: u32) -> Self {
+        self.status = status;
+        self
+    }
+    pub fn with_proc_type(mut self, t: impl Into<String>) -> Self {
+        self.proc_type = t.into();
+        self
+    }
+    pub fn with_boot(mut self, boot: BootInfo) -> Self {
+        self.boot = Some(boot);
+        self
+    }
+    pub fn with_source(mut self, source: Source) -> Self {
+        self.source = source;
+        self
+    }
+    /// Length in blocks, checked against the size of the data
+    fn blocks(&self) -> Result<u32, ApmError> {
+        let len = self.source.len()?;
+        let needed = u32::try_from(len.div_ceil(512))
+            .map_err(|_| ApmError::DataTooLarge(len as usize, u32::MAX))?;
+        match self.size.unwrap_or(needed) {
+            0 => Err(ApmError::EmptyPartition),
+            size if size < needed => Err(ApmError::DataTooLarge(len as usize, size)),
+            size => Ok(size),
+        }
+    }
+    /// Map entry of the partition, without anything depending on its data
+    fn entry(&self, start: u32, length: u32) -> PartitionEntry {
+        PartitionEntry::new()
+            .with_start(start)
+            .with_length(length)
+            .with_name(self.name.clone())
+            .with_type(self.ty.clone())
+            .with_status(self.status)
+            .with_proc_type(self.proc_type.clone())
+    }
+}
+
+/// A driver to be created by `ApmBuilder`, in a partition of its own registered in the DDM
+#[derive(Clone, Debug)]
+pub struct DriverSpec {
+    ty: DriverType,
+    partition: PartitionSpec,
+}
+
+impl DriverSpec {
+    /// The driver is the boot code of `partition`, see `PartitionSpec::driver`
+    pub fn new(ty: DriverType, partition: PartitionSpec) -> Self {
+        Self { ty, partition }
+    }
+    /// First block of the driver code in its partition, and its size in bytes
+    fn code(&self) -> Result<(u32, u64), ApmError> {
+        Ok(match self.partition.boot {
+            Some(boot) if boot.size > 0 => (boot.start, boot.size as u64),
+            boot => {
+                let start = boot.map_or(0, |b| b.start);
+                (start, self.partition.source.len()?.saturating_sub(start as u64*512))
+            },
+        })
+    }
+}
+
+/// Builds a disk from descriptions of its drivers and partitions, checking that all of them
+/// fit together before reading any data
+#[derive(Clone, Debug)]
+pub struct ApmBuilder {
+    blocks: u32,
+    map_len: u32,
+    strategy: Strategy,
+    drivers: Vec<DriverSpec>,
+    partitions: Vec<PartitionSpec>,
+}
+
+impl ApmBuilder {
+    /// A disk of `blocks` blocks, with a partition map of 63 entries
+    pub fn new(blocks: u32) -> Self {
+        Self {
+            blocks,
+            map_len: 0x3f,
+            strategy: Strategy::FirstFit,
+            drivers: Vec::new(),
+            partitions: Vec::new(),
+        }
+    }
+    /// Number of blocks of the partition map, each holding one entry
+    pub fn with_map_len(mut self, blocks: u32) -> Self {
+        self.map_len = blocks;
+        self
+    }
+    /// How space is picked for drivers and partitions without a first block
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+    pub fn with_driver(mut self, driver: DriverSpec) -> Self {
+        self.drivers.push(driver);
+        self
+    }
+    pub fn with_partition(mut self, partition: PartitionSpec) -> Self {
+        self.partitions.push(partition);
+        self
+    }
+    fn specs(&self) -> impl Iterator<Item = &PartitionSpec> {
+        self.drivers.iter()
+            .map(|d| &d.partition)
+            .chain(self.partitions.iter())
+    }
+    /// Places every driver and then every partition, returning their first blocks and lengths
+    /// in that order. Those given a first block are placed before the others.
+    pub fn layout(&self) -> Result<Vec<(u32, u32)>, ApmError> {
+        let count = self.drivers.len() + self.partitions.len();
+        if 1 + count > self.map_len as usize {
+            return Err(ApmError::MapFull);
+        }
+        let map_end = 1 + self.map_len;
+        if map_end > self.blocks {
+            return Err(ApmError::OutOfBounds(1, map_end));
+        }
+        let sizes = self.specs()
+            .map(|spec| {
+                spec.entry(0, 0).validate()?;
+                spec.blocks()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (driver, size) in self.drivers.iter().zip(&sizes) {
+            let (start, code) = driver.code()?;
+            if code.div_ceil(512) > u16::MAX as u64 {
+                return Err(ApmError::DataTooLarge(code as usize, u16::MAX as u32));
+            }
+            // The code must lie within the partition for the DDM to point at it
+            if code == 0 {
+                return Err(ApmError::DataTooSmall(0, 1));
+            }
+            let room = size.saturating_sub(start);
+            if code.div_ceil(512) > room as u64 {
+                return Err(ApmError::DataTooLarge(code as usize, room));
+            }
+        }
+
+        let mut used = vec![(0, map_end)];
+        let mut ret = vec![None; count];
+        for (i, spec) in self.specs().enumerate() {
+            let Some(start) = spec.start else {
+                continue;
+            };
+            let end = start.checked_add(sizes[i])
+                .filter(|end| *end <= self.blocks)
+                .ok_or(ApmError::OutOfBounds(start, start.saturating_add(sizes[i])))?;
+            if start % spec.align != 0 {
+                return Err(ApmError::Misaligned(start, spec.align));
+            }
+            if used.iter().any(|(s, e)| start < *e && *s < end) {
+                return Err(ApmError::Overlap(start, end));
+            }
+            used.push((start, end));
+            ret[i] = Some((start, sizes[i]));
+        }
+        for (i, spec) in self.specs().enumerate() {
+            if spec.start.is_some() {
+                continue;
+            }
+            let start = AllocPolicy::new()
+                .with_strategy(self.strategy)
+                .with_align(spec.align)
+                .place(&holes_between(used.clone(), self.blocks), sizes[i])?;
+            used.push((start, start + sizes[i]));
+            ret[i] = Some((start, sizes[i]));
+        }
+        Ok(ret.into_iter().flatten().collect())
+    }
+    /// Checks the layout, then reads the data of every driver and partition into a new map
+    pub fn build(self) -> Result<ApmMap, ApmError> {
+        let layout = self.layout()?;
+        let mut map = ApmMap::new(self.blocks);
+        map.partitions[0].set_length(self.map_len);
+
+        let specs = self.drivers.into_iter()
+            .map(|d| (Some(d.ty), d.partition))
+            .chain(self.partitions.into_iter().map(|p| (None, p)));
+        for ((ty, spec), (start, length)) in specs.zip(layout) {
+            let mut entry = spec.entry(start, length);
+            let mut boot = spec.boot.unwrap_or_default();
+            let data = spec.source.read()?;
+            if ty.is_some() && boot.size == 0 {
+                boot.size = data.len().saturating_sub(boot.start as usize*512) as u32;
+            }
+            let code = data.get(boot.start as usize*512..).unwrap_or(&[]);
+            let code = &code[..code.len().min(boot.size as usize)];
+            entry.boot_start = boot.start;
+            entry.boot_size = boot.size;
+            entry.boot_load_address = boot.load_address;
+            entry.boot_entry = boot.entry;
+            entry.boot_checksum = match (boot.checksum, boot.size) {
+                (Some(checksum), _) => checksum,
+                (None, 0) => 0,
+                (None, _) => apple_checksum(code) as u32,
+            };
+            map.insert_partition(entry, &data)?;
+            if let Some(ty) = ty {
+                map.driver_desc.push_driver_data(DriverData::new(start + boot.start, boot.size.div_ceil(512) as u16, ty));
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(blocks: u32) -> PartitionSpec {
+        PartitionSpec::new("Part", "Apple_HFS").with_size(blocks)
+    }
+
+    #[test]
+    fn layout_places_fixed_partitions_first() {
+        let builder = ApmBuilder::new(1000)
+            .with_partition(part(100))
+            .with_partition(part(50).with_start(64))
+            .with_partition(part(10).with_align(8));
+        assert_eq!(builder.layout().unwrap(), [(114, 100), (64, 50), (216, 10)]);
+    }
+
+    #[test]
+    fn layout_rejects_conflicts() {
+        let layout = |parts: &[PartitionSpec]| {
+            parts.iter().cloned().fold(ApmBuilder::new(1000), ApmBuilder::with_partition).layout()
+        };
+        assert!(matches!(layout(&[part(50).with_start(64), part(10).with_start(100)]), Err(ApmError::Overlap(100, 110))));
+        assert!(matches!(layout(&[part(10).with_start(10)]), Err(ApmError::Overlap(10, 20))));
+        assert!(matches!(layout(&[part(10).with_start(990), part(10).with_start(985)]), Err(ApmError::Overlap(985, 995))));
+        assert!(matches!(layout(&[part(20).with_start(990)]), Err(ApmError::OutOfBounds(990, 1010))));
+        assert!(matches!(layout(&[part(10).with_start(65).with_align(4)]), Err(ApmError::Misaligned(65, 4))));
+        assert!(matches!(layout(&[PartitionSpec::new("Empty", "Apple_HFS")]), Err(ApmError::EmptyPartition)));
+        // Fixed partitions leave too little room for the others
+        assert!(matches!(layout(&[part(900), part(10).with_start(500)]), Err(ApmError::NoSpace)));
+    }
+
+    #[test]
+    fn layout_checks_map_size() {
+        let full = (0..63).fold(ApmBuilder::new(10000), |b, _| b.with_partition(part(1)));
+        assert!(matches!(full.layout(), Err(ApmError::MapFull)));
+        let full = (0..3).fold(ApmBuilder::new(10000).with_map_len(3), |b, _| b.with_partition(part(1)));
+        assert!(matches!(full.layout(), Err(ApmError::MapFull)));
+        let large = ApmBuilder::new(100).with_map_len(100).with_partition(part(1));
+        assert!(matches!(large.layout(), Err(ApmError::OutOfBounds(1, 101))));
+    }
+
+    #[test]
+    fn build_registers_driver_code() {
+        let code: Vec<u8> = (0..3*512).map(|i| i as u8).collect();
+        let boot = BootInfo { start: 1, ..BootInfo::default() };
+        let map = ApmBuilder::new(1000)
+            .with_driver(DriverSpec::new(DriverType::MacOs68k, PartitionSpec::driver(DriverType::MacOs68k, Source::Bytes(code.clone()))))
+            .with_driver(DriverSpec::new(DriverType::AUX, PartitionSpec::driver(DriverType::AUX, Source::Bytes(code.clone())).with_boot(boot).with_start(200)))
+            .with_partition(part(100))
+            .build()
+            .unwrap();
+        let ddm: Vec<(u32, u16, DriverType)> = map.driver_desc.drivers.iter()
+            .map(|d| (d.start(), d.size(), d.driver_type()))
+            .collect();
+        assert_eq!(ddm, [(64, 3, DriverType::MacOs68k), (201, 2, DriverType::AUX)]);
+
+        let entry = map.partitions.iter().find(|p| p.start() == 200).unwrap();
+        assert_eq!((entry.start(), entry.boot_start(), entry.boot_size()), (200, 1, 1024));
+        assert_eq!(entry.boot_checksum(), apple_checksum(&code[512..]) as u32);
+    }
+
+    #[test]
+    fn build_rejects_code_outside_the_partition() {
+        let boot = BootInfo { start: 2, size: 1024, ..BootInfo::default() };
+        let spec = PartitionSpec::driver(DriverType::MacOs68k, Source::Bytes(vec![1; 3*512])).with_boot(boot);
+        let builder = ApmBuilder::new(1000).with_driver(DriverSpec::new(DriverType::MacOs68k, spec));
+        assert!(matches!(builder.build(), Err(ApmError::DataTooLarge(1024, 1))));
+    }
+}
@@ -0,0 +1,332 @@
+//! Detection of filesystems stored in partitions
+
+use std::fmt;
+
+use crate::macroman;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filesystem {
+    /// Macintosh File System, used by the earliest Macs
+    Mfs,
+    /// Hierarchical File System
+    Hfs,
+    /// HFS volume acting as a wrapper around an embedded HFS+ volume
+    HfsWrapper,
+    HfsPlus,
+    /// Case-sensitive variant of HFS+
+    Hfsx,
+    ProDos,
+    /// Berkeley Fast File System, as used by A/UX and the BSDs
+    Ufs,
+    Ext2,
+    Ext3,
+    Ext4,
+    Iso9660,
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl Filesystem {
+    /// Partition types this filesystem is normally found in
+    pub fn partition_types(&self) -> &'static [&'static str] {
+        match self {
+            Self::Mfs | Self::Hfs | Self::HfsWrapper | Self::HfsPlus | Self::Hfsx => &["Apple_HFS", "Apple_HFSX"],
+            Self::ProDos => &["Apple_PRODOS"],
+            Self::Ufs => &["Apple_UNIX_SVR2", "Apple_UFS"],
+            Self::Ext2 | Self::Ext3 | Self::Ext4 => &["Apple_UNIX_SVR2", "Linux", "Apple_Linux"],
+            Self::Iso9660 => &["CD_partition_scheme", "Apple_HFS"],
+            Self::Fat12 | Self::Fat16 | Self::Fat32 => &["DOS_FAT_12", "DOS_FAT_16", "DOS_FAT_32", "Apple_FAT"],
+        }
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Mfs => "MFS",
+            Self::Hfs => "HFS",
+            Self::HfsWrapper => "HFS wrapper with embedded HFS+",
+            Self::HfsPlus => "HFS+",
+            Self::Hfsx => "HFSX",
+            Self::ProDos => "ProDOS",
+            Self::Ufs => "UFS",
+            Self::Ext2 => "ext2",
+            Self::Ext3 => "ext3",
+            Self::Ext4 => "ext4",
+            Self::Iso9660 => "ISO 9660",
+            Self::Fat12 => "FAT12",
+            Self::Fat16 => "FAT16",
+            Self::Fat32 => "FAT32",
+        })
+    }
+}
+
+/// A filesystem found at the start of some data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Detected {
+    pub fs: Filesystem,
+    /// Name of the volume, if the filesystem stores one and it is not empty
+    pub name: Option<String>,
+    /// Size of the volume in bytes, as recorded in the filesystem
+    pub size: u64,
+    /// Allocation unit of the filesystem in bytes, partitions may be up to one unit larger
+    /// than the volume
+    pub block_size: u64,
+}
+
+impl Detected {
+    /// Describes how the volume disagrees with the partition of `part_type` and `len` bytes
+    /// holding it
+    pub fn mismatches(&self, part_type: &str, len: u64) -> Vec<String> {
+        let mut ret = Vec::new();
+        if self.size > len {
+            ret.push(format!("{} volume is {} bytes, larger than its {} byte partition", self.fs, self.size, len));
+        } else if self.size + self.block_size <= len {
+            ret.push(format!("{} volume is {} bytes, smaller than its {} byte partition", self.fs, self.size, len));
+        }
+        let known = ALL.iter()
+            .any(|fs| fs.partition_types().contains(&part_type));
+        if known && !self.fs.partition_types().contains(&part_type) {
+            ret.push(format!("{} volume is in a partition of type '{}'", self.fs, part_type));
+        }
+        ret
+    }
+}
+
+const ALL: [Filesystem; 14] = [
+    Filesystem::Mfs, Filesystem::Hfs, Filesystem::HfsWrapper, Filesystem::HfsPlus, Filesystem::Hfsx,
+    Filesystem::ProDos, Filesystem::Ufs, Filesystem::Ext2, Filesystem::Ext3, Filesystem::Ext4,
+    Filesystem::Iso9660, Filesystem::Fat12, Filesystem::Fat16, Filesystem::Fat32,
+];
+
+fn be16(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+fn le16(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn le32(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?))
+}
+
+/// The bytes of a fixed-size string up to the first NUL
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    &bytes[..bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len())]
+}
+
+/// Drops trailing padding from a volume name, `None` if nothing is left
+fn trimmed(name: &str) -> Option<String> {
+    let name = name.trim_end();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Turns a fixed-size or Pascal string in MacRoman, of which ASCII is a subset, into a volume
+/// name
+fn name(bytes: &[u8]) -> Option<String> {
+    trimmed(&macroman::decode(until_nul(bytes)))
+}
+
+fn pascal_name(data: &[u8], off: usize, max: usize) -> Option<String> {
+    let len = (*data.get(off)? as usize).min(max);
+    name(data.get(off + 1..off + 1 + len)?)
+}
+
+/// Number of bytes at the start of a partition that `detect` looks at
+pub const PROBE_LEN: usize = 32768 + 2048;
+
+/// Detects the filesystem stored at the start of `data`
+pub fn detect(data: &[u8]) -> Option<Detected> {
+    detect_apple(data)
+        .or_else(|| detect_ext(data))
+        .or_else(|| detect_ufs(data))
+        .or_else(|| detect_iso9660(data))
+        .or_else(|| detect_prodos(data))
+        .or_else(|| detect_fat(data))
+}
+
+fn detect_apple(data: &[u8]) -> Option<Detected> {
+    let mdb = data.get(1024..1536)?;
+    match &mdb[..2] {
+        b"H+" | b"HX" => {
+            let fs = if &mdb[..2] == b"H+" { Filesystem::HfsPlus } else { Filesystem::Hfsx };
+            let block_size = be32(mdb, 40)? as u64;
+            let size = block_size * be32(mdb, 44)? as u64;
+            Some(Detected { fs, name: None, size, block_size })
+        },
+        b"BD" | [0xd2, 0xd7] => {
+            let blocks = be16(mdb, 18)? as u64;
+            let block_size = be32(mdb, 20)? as u64;
+            let first = be16(mdb, 28)? as u64;
+            let fs = match (&mdb[..2], &mdb[124..126]) {
+                (b"BD", b"H+") => Filesystem::HfsWrapper,
+                (b"BD", _) => Filesystem::Hfs,
+                _ => Filesystem::Mfs,
+            };
+            // Allocation blocks are followed by the alternate MDB and a reserved block
+            let size = first*512 + blocks*block_size + 1024;
+            Some(Detected { fs, name: pascal_name(mdb, 36, 27), size, block_size })
+        },
+        _ => None,
+    }
+}
+
+fn detect_ext(data: &[u8]) -> Option<Detected> {
+    let sb = data.get(1024..2048)?;
+    if le16(sb, 56)? != 0xef53 {
+        return None;
+    }
+    let block_size = 1024u64 << le32(sb, 24)?.min(16);
+    let mut blocks = le32(sb, 4)? as u64;
+    let (compat, incompat) = (le32(sb, 92)?, le32(sb, 96)?);
+    if incompat & 0x80 != 0 {
+        blocks |= (le32(sb, 336)? as u64) << 32;
+    }
+    let fs = if incompat & (0x40 | 0x80 | 0x200) != 0 {
+        Filesystem::Ext4
+    } else if compat & 0x4 != 0 {
+        Filesystem::Ext3
+    } else {
+        Filesystem::Ext2
+    };
+    Some(Detected { fs, name: trimmed(&String::from_utf8_lossy(until_nul(&sb[120..136]))), size: blocks*block_size, block_size })
+}
+
+fn detect_ufs(data: &[u8]) -> Option<Detected> {
+    let sb = data.get(8192..8192 + 1376)?;
+    let (size, frag) = match &sb[1372..1376] {
+        [0x00, 0x01, 0x19, 0x54] => (be32(sb, 36)?, be32(sb, 52)?),
+        [0x54, 0x19, 0x01, 0x00] => (le32(sb, 36)?, le32(sb, 52)?),
+        _ => return None,
+    };
+    Some(Detected { fs: Filesystem::Ufs, name: None, size: size as u64 * frag as u64, block_size: frag as u64 })
+}
+
+fn detect_iso9660(data: &[u8]) -> Option<Detected> {
+    let pvd = data.get(32768..32768 + 2048)?;
+    if &pvd[1..6] != b"CD001" {
+        return None;
+    }
+    let block_size = le16(pvd, 128)? as u64;
+    let size = le32(pvd, 80)? as u64 * block_size;
+    Some(Detected { fs: Filesystem::Iso9660, name: name(&pvd[40..72]), size, block_size })
+}
+
+fn detect_prodos(data: &[u8]) -> Option<Detected> {
+    let block = data.get(1024..1536)?;
+    // Volume directory header with the standard entry layout
+    if le16(block, 0)? != 0 || block[4] >> 4 != 0xf || block[0x23] != 0x27 || block[0x24] != 0x0d {
+        return None;
+    }
+    let len = (block[4] & 0xf) as usize;
+    let size = le16(block, 0x29)? as u64 * 512;
+    Some(Detected { fs: Filesystem::ProDos, name: name(&block[5..5 + len]), size, block_size: 512 })
+}
+
+fn detect_fat(data: &[u8]) -> Option<Detected> {
+    let bs = data.get(..512)?;
+    if bs[510..] != [0x55, 0xaa] || !matches!(bs[0], 0xeb | 0xe9) {
+        return None;
+    }
+    let sector = le16(bs, 11)? as u64;
+    let sectors = match le16(bs, 19)? {
+        0 => le32(bs, 32)? as u64,
+        n => n as u64,
+    };
+    let (fs, label) = match (&bs[82..87], &bs[54..59]) {
+        (b"FAT32", _) => (Filesystem::Fat32, &bs[71..82]),
+        (_, b"FAT12") => (Filesystem::Fat12, &bs[43..54]),
+        (_, b"FAT16") => (Filesystem::Fat16, &bs[43..54]),
+        _ => return None,
+    };
+    let name = name(label).filter(|n| n != "NO NAME");
+    Some(Detected { fs, name, size: sectors*sector, block_size: sector })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(fields: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut ret = vec![0; PROBE_LEN];
+        for (off, bytes) in fields {
+            ret[*off..][..bytes.len()].copy_from_slice(bytes);
+        }
+        ret
+    }
+
+    fn detected(fs: Filesystem, name: Option<&str>, size: u64, block_size: u64) -> Option<Detected> {
+        Some(Detected { fs, name: name.map(str::to_string), size, block_size })
+    }
+
+    /// An MDB of 100 allocation blocks of 1024 bytes starting at block 5
+    fn mdb(sig: &[u8], name: &[u8], embed: &[u8]) -> Vec<u8> {
+        let mut pascal = vec![name.len() as u8];
+        pascal.extend_from_slice(name);
+        image(&[(1024, sig), (1042, &[0, 100, 0, 0, 4, 0]), (1052, &[0, 5]), (1060, &pascal), (1148, embed)])
+    }
+
+    #[test]
+    fn detect_apple_filesystems() {
+        let size = 5*512 + 100*1024 + 1024;
+        let name = macroman::encode("Café ƒ").unwrap();
+        assert_eq!(detect(&mdb(b"BD", &name, b"")), detected(Filesystem::Hfs, Some("Café ƒ"), size, 1024));
+        assert_eq!(detect(&mdb(&[0xd2, 0xd7], b"Old  ", b"")), detected(Filesystem::Mfs, Some("Old"), size, 1024));
+        assert_eq!(detect(&mdb(b"BD", b"Wrapper", b"H+")), detected(Filesystem::HfsWrapper, Some("Wrapper"), size, 1024));
+
+        let plus = |sig: &[u8]| image(&[(1024, sig), (1064, &[0, 0, 16, 0, 0, 0, 0, 10])]);
+        assert_eq!(detect(&plus(b"H+")), detected(Filesystem::HfsPlus, None, 40960, 4096));
+        assert_eq!(detect(&plus(b"HX")), detected(Filesystem::Hfsx, None, 40960, 4096));
+
+        let prodos = image(&[(1024 + 4, &[0xf4, b'D', b'i', b's', b'k']), (1024 + 0x23, &[0x27, 0x0d]), (1024 + 0x29, &[0x18, 0x01])]);
+        assert_eq!(detect(&prodos), detected(Filesystem::ProDos, Some("Disk"), 280*512, 512));
+    }
+
+    #[test]
+    fn detect_unix_filesystems() {
+        let ufs = image(&[(8192 + 36, &[0, 0, 1, 0]), (8192 + 52, &[0, 0, 4, 0]), (8192 + 1372, &[0x00, 0x01, 0x19, 0x54])]);
+        assert_eq!(detect(&ufs), detected(Filesystem::Ufs, None, 256*1024, 1024));
+        let ufs = image(&[(8192 + 36, &[0, 1, 0, 0]), (8192 + 52, &[0, 4, 0, 0]), (8192 + 1372, &[0x54, 0x19, 0x01, 0x00])]);
+        assert_eq!(detect(&ufs), detected(Filesystem::Ufs, None, 256*1024, 1024));
+
+        let ext = |compat: u8, incompat: u8, label: &str| image(&[
+            (1024 + 4, &[0, 1, 0, 0]),
+            (1024 + 24, &[2]),
+            (1024 + 56, &[0x53, 0xef]),
+            (1024 + 92, &[compat]),
+            (1024 + 96, &[incompat]),
+            (1024 + 120, label.as_bytes()),
+        ]);
+        assert_eq!(detect(&ext(0, 0, "")), detected(Filesystem::Ext2, None, 256*4096, 4096));
+        assert_eq!(detect(&ext(4, 0, "root")), detected(Filesystem::Ext3, Some("root"), 256*4096, 4096));
+        assert_eq!(detect(&ext(4, 0x40, "Grüße")), detected(Filesystem::Ext4, Some("Grüße"), 256*4096, 4096));
+    }
+
+    #[test]
+    fn detect_other_filesystems() {
+        let label = format!("{:32}", "CDROM");
+        let iso = image(&[(32769, b"CD001"), (32768 + 40, label.as_bytes()), (32768 + 80, &[100]), (32768 + 128, &[0, 8])]);
+        assert_eq!(detect(&iso), detected(Filesystem::Iso9660, Some("CDROM"), 100*2048, 2048));
+
+        let fat = |fields: &[(usize, &[u8])]| {
+            let mut ret = image(&[(0, &[0xeb]), (11, &[0, 2]), (19, &[0, 8]), (510, &[0x55, 0xaa])]);
+            for (off, bytes) in fields {
+                ret[*off..][..bytes.len()].copy_from_slice(bytes);
+            }
+            ret
+        };
+        assert_eq!(detect(&fat(&[(43, b"NO NAME    FAT12")])), detected(Filesystem::Fat12, None, 2048*512, 512));
+        assert_eq!(detect(&fat(&[(43, b"DATA       FAT16")])), detected(Filesystem::Fat16, Some("DATA"), 2048*512, 512));
+        assert_eq!(detect(&fat(&[(19, &[0, 0]), (32, &[0, 0, 1]), (71, b"BIG        FAT32")])),
+            detected(Filesystem::Fat32, Some("BIG"), 65536*512, 512));
+
+        assert_eq!(detect(&image(&[])), None);
+        assert_eq!(detect(&[0; 100]), None);
+    }
+}
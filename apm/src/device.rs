@@ -0,0 +1,393 @@
+//! Positioned access to disk images and block devices, which are too large to read whole
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ApmError;
+
+/// A disk image or block device, read and written at arbitrary offsets
+#[derive(Debug)]
+pub struct Device {
+    file: fs::File,
+    size: u64,
+    sector_size: u32,
+    block_device: bool,
+    /// Offset used by the `Read`, `Write` and `Seek` implementations
+    pos: u64,
+    journal: Option<Journal>,
+}
+
+/// Start of every undo journal, followed by the size of the device when it was started
+const JOURNAL_MAGIC: &[u8; 8] = b"APMUNDO1";
+
+/// An undo journal, holding everything overwritten since it was started as records of an offset,
+/// a length and the old bytes. Records only count once they reached the disk, before the data
+/// they describe is overwritten.
+#[derive(Debug)]
+struct Journal {
+    file: fs::File,
+    path: PathBuf,
+    /// Size of the device when the journal was started, nothing after it needs to be saved
+    size: u64,
+}
+
+impl Device {
+    /// Opens an image or a device. Devices are refused for writing while they or any of their
+    /// partitions are mounted.
+    pub fn open(path: &Path, writable: bool) -> Result<Self, ApmError> {
+        let block_device = is_block_device(&fs::metadata(path)?);
+        if writable {
+            ensure_unused(path)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.read(true).write(writable);
+        #[cfg(target_os = "linux")]
+        if writable && block_device {
+            // Claims the device, which fails while the kernel uses it in ways /proc/mounts misses
+            std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_EXCL);
+        }
+        let file = options.open(path)
+            .map_err(|e| match e.raw_os_error() {
+                #[cfg(target_os = "linux")]
+                Some(libc::EBUSY) => ApmError::Mounted(path.display().to_string(), "in use".to_string()),
+                _ => e.into(),
+            })?;
+        Self::from_file(file)
+    }
+    /// Wraps an already opened image or device
+    pub fn from_file(file: fs::File) -> Result<Self, ApmError> {
+        let meta = file.metadata()?;
+        let block_device = is_block_device(&meta);
+        let (size, sector_size) = match block_device {
+            true => sys::geometry(&file)?,
+            false => (meta.len(), 512),
+        };
+        Ok(Self { file, size, sector_size, block_device, pos: 0, journal: None })
+    }
+    /// Size in bytes, as reported by the kernel for block devices
+    pub fn size(&self) -> u64 { self.size }
+    /// Logical sector size, all I/O is done in whole sectors
+    pub fn sector_size(&self) -> u32 { self.sector_size }
+    pub fn is_block_device(&self) -> bool { self.block_device }
+    /// Fills `buf` with the bytes starting at `offset`
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let (start, end) = self.sectors(offset, buf.len());
+        if (start, end) == (offset, offset + buf.len() as u64) {
+            return self.read_exact_at(offset, buf);
+        }
+        let mut sectors = vec![0; (end - start) as usize];
+        self.read_exact_at(start, &mut sectors)?;
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&sectors[skip..][..buf.len()]);
+        Ok(())
+    }
+    /// Writes `buf` starting at `offset`, reading partially covered sectors first
+    pub fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let (start, end) = self.sectors(offset, buf.len());
+        self.save_old(start, end)?;
+        if (start, end) == (offset, offset + buf.len() as u64) {
+            return self.write_all_at(offset, buf);
+        }
+        let mut sectors = vec![0; (end - start) as usize];
+        self.read_exact_at(start, &mut sectors)?;
+        let skip = (offset - start) as usize;
+        sectors[skip..][..buf.len()].copy_from_slice(buf);
+        self.write_all_at(start, &sectors)
+    }
+    /// Starts saving everything overwritten from now on to a new undo journal at `path`, so that
+    /// [`Device::undo`] can bring the device back to its current state, including its size.
+    pub fn start_journal(&mut self, path: &Path) -> Result<(), ApmError> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all(JOURNAL_MAGIC)?;
+        file.write_all(&self.size.to_be_bytes())?;
+        file.sync_all()?;
+        self.journal = Some(Journal { file, path: path.to_path_buf(), size: self.size });
+        Ok(())
+    }
+    /// Makes sure everything written reached the disk, then deletes the undo journal
+    pub fn end_journal(&mut self) -> Result<(), ApmError> {
+        self.sync()?;
+        if let Some(journal) = self.journal.take() {
+            fs::remove_file(&journal.path)?;
+        }
+        Ok(())
+    }
+    /// Writes back everything saved in the undo journal at `path`, newest first, then deletes it.
+    /// A record cut short by a crash is ignored, as the data it describes was not overwritten.
+    pub fn undo(&mut self, path: &Path) -> Result<(), ApmError> {
+        self.journal = None;
+        let mut data = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut data)?;
+        if data.len() < 16 || &data[..8] != JOURNAL_MAGIC {
+            return Err(ApmError::BadJournal(path.display().to_string()));
+        }
+        let size = u64::from_be_bytes(data[8..16].try_into().unwrap());
+        let mut records = Vec::new();
+        let mut rest = &data[16..];
+        while rest.len() >= 16 {
+            let offset = u64::from_be_bytes(rest[..8].try_into().unwrap());
+            let len = u64::from_be_bytes(rest[8..16].try_into().unwrap());
+            if (rest.len() as u64 - 16) < len {
+                break;
+            }
+            records.push((offset, &rest[16..][..len as usize]));
+            rest = &rest[16 + len as usize..];
+        }
+        // Data cut off by shrinking is only written back once the image has its old size again
+        if !self.block_device && self.size != size {
+            self.set_len(size)?;
+        }
+        for (offset, old) in records.into_iter().rev() {
+            self.write_at(offset, old)?;
+        }
+        self.sync()?;
+        fs::remove_file(path)?;
+        Ok(())
+    }
+    /// Saves the bytes about to be overwritten at `start..end` to the undo journal, if there is one
+    fn save_old(&self, start: u64, end: u64) -> io::Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let end = end.min(journal.size);
+        if start >= end {
+            return Ok(());
+        }
+        let mut record = Vec::with_capacity(16 + (end - start) as usize);
+        record.extend_from_slice(&start.to_be_bytes());
+        record.extend_from_slice(&(end - start).to_be_bytes());
+        record.resize(16 + (end - start) as usize, 0);
+        self.read_exact_at(start, &mut record[16..])?;
+        (&journal.file).write_all(&record)?;
+        journal.file.sync_data()
+    }
+    /// Truncates or extends an image to `size` bytes, saving what is cut off to the undo journal.
+    /// Block devices keep their size, so they are only checked to hold that many bytes.
+    pub fn set_len(&mut self, size: u64) -> Result<(), ApmError> {
+        if self.block_device {
+            if size > self.size {
+                return Err(ApmError::OutOfBounds((self.size/512) as u32, size.div_ceil(512) as u32));
+            }
+            return Ok(());
+        }
+        if size < self.size {
+            self.save_old(size, self.size)?;
+        }
+        self.file.set_len(size)?;
+        self.size = size;
+        Ok(())
+    }
+    /// Makes sure everything written reached the disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+    /// Byte range of the sectors covering `len` bytes at `offset`
+    fn sectors(&self, offset: u64, len: usize) -> (u64, u64) {
+        let sector = self.sector_size as u64;
+        (offset / sector * sector, (offset + len as u64).next_multiple_of(sector))
+    }
+    #[cfg(unix)]
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, buf, offset)
+    }
+    #[cfg(unix)]
+    fn write_all_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(&self.file, buf, offset)
+    }
+    #[cfg(not(unix))]
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        use std::io::{Read, Seek};
+        let mut file = &self.file;
+        file.seek(io::SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+    #[cfg(not(unix))]
+    fn write_all_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        use std::io::{Seek, Write};
+        let mut file = &self.file;
+        file.seek(io::SeekFrom::Start(offset))?;
+        file.write_all(buf)
+    }
+}
+
+impl io::Read for Device {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.size.saturating_sub(self.pos)) as usize;
+        self.read_at(self.pos, &mut buf[..len])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl io::Write for Device {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at(self.pos, buf)?;
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl io::Seek for Device {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            io::SeekFrom::Start(p) => Some(p),
+            io::SeekFrom::End(d) => self.size.checked_add_signed(d),
+            io::SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = new.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the device"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Fails if `path` is a block device which is mounted, or has a mounted partition
+pub fn ensure_unused(path: &Path) -> Result<(), ApmError> {
+    if !is_block_device(&fs::metadata(path)?) {
+        return Ok(());
+    }
+    match sys::mounted(path)? {
+        Some(mount) => Err(ApmError::Mounted(path.display().to_string(), mount)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn is_block_device(meta: &fs::Metadata) -> bool {
+    std::os::unix::fs::FileTypeExt::is_block_device(&meta.file_type())
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    #[cfg(any(target_arch = "mips", target_arch = "mips64", target_arch = "powerpc",
+              target_arch = "powerpc64", target_arch = "sparc", target_arch = "sparc64"))]
+    const IOC_READ: u32 = 2 << 29;
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64", target_arch = "powerpc",
+                  target_arch = "powerpc64", target_arch = "sparc", target_arch = "sparc64")))]
+    const IOC_READ: u32 = 2 << 30;
+    /// `_IOR(0x12, 114, u64)`, missing from libc
+    const BLKGETSIZE64: u32 = IOC_READ | (8 << 16) | (0x12 << 8) | 114;
+
+    /// Size in bytes and logical sector size of a block device
+    pub fn geometry(file: &fs::File) -> io::Result<(u64, u32)> {
+        let mut size: u64 = 0;
+        let mut sector_size: libc::c_int = 0;
+        // SAFETY: both requests store a single integer of the type passed to them
+        let ok = unsafe {
+            libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) == 0
+                && libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET as _, &mut sector_size) == 0
+        };
+        if !ok {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((size, (sector_size as u32).max(512)))
+    }
+
+    /// Finds a mount or swap area on `path` or one of its partitions, returning its source
+    pub fn mounted(path: &Path) -> io::Result<Option<String>> {
+        let device = fs::canonicalize(path)?;
+        let rdev = fs::metadata(&device)?.rdev();
+        let name = device.file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        for table in ["/proc/mounts", "/proc/swaps"] {
+            let Ok(contents) = fs::read_to_string(table) else {
+                continue;
+            };
+            for source in contents.lines().filter_map(|l| l.split_whitespace().next()) {
+                let Ok(source_path) = fs::canonicalize(source) else {
+                    continue;
+                };
+                let Ok(meta) = fs::metadata(&source_path) else {
+                    continue;
+                };
+                // Partitions show up in sysfs below the disk they are on
+                let partition = source_path.file_name()
+                    .is_some_and(|n| Path::new("/sys/class/block").join(&name).join(n).exists());
+                if super::is_block_device(&meta) && (meta.rdev() == rdev || partition) {
+                    return Ok(Some(source.to_string()));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::fs;
+    use std::io::{self, Seek};
+    use std::path::Path;
+
+    pub fn geometry(mut file: &fs::File) -> io::Result<(u64, u32)> {
+        Ok((file.seek(io::SeekFrom::End(0))?, 512))
+    }
+
+    pub fn mounted(_path: &Path) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_restores_after_interrupted_writes() {
+        let dir = std::env::temp_dir().join(format!("apm-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (image, journal) = (dir.join("disk.img"), dir.join("disk.journal"));
+        let old: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        fs::write(&image, &old).unwrap();
+
+        let mut device = Device::open(&image, true).unwrap();
+        device.start_journal(&journal).unwrap();
+        device.write_at(1000, &[0xaa; 3000]).unwrap();
+        device.write_at(512, &[0x55; 512]).unwrap();
+        device.set_len(16384).unwrap();
+        device.write_at(12288, &[1; 512]).unwrap();
+        // Dropped without ending the journal, as if the process had crashed
+        drop(device);
+        assert_ne!(fs::read(&image).unwrap(), old);
+
+        Device::open(&image, true).unwrap().undo(&journal).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), old);
+        assert!(!journal.exists());
+
+        let mut device = Device::open(&image, true).unwrap();
+        device.start_journal(&journal).unwrap();
+        device.write_at(0, &[2; 512]).unwrap();
+        device.end_journal().unwrap();
+        assert!(!journal.exists());
+        assert_eq!(fs::read(&image).unwrap()[..512], [2; 512]);
+
+        // Data cut off by shrinking comes back as well
+        let old = fs::read(&image).unwrap();
+        let mut device = Device::open(&image, true).unwrap();
+        device.start_journal(&journal).unwrap();
+        device.set_len(1024).unwrap();
+        device.write_at(600, &[3; 100]).unwrap();
+        drop(device);
+        assert_eq!(fs::metadata(&image).unwrap().len(), 1024);
+        Device::open(&image, true).unwrap().undo(&journal).unwrap();
+        assert_eq!(fs::read(&image).unwrap(), old);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
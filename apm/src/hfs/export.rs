@@ -0,0 +1,118 @@
+//! Writing of files from volumes to host filesystems, which have no resource forks
+
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::Path;
+
+use super::{system_time, EntryKind, File, HfsError, Volume};
+use crate::{appledouble, macbinary};
+
+/// How resource forks and Finder information are kept on a host filesystem
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Only the data fork
+    #[default]
+    Raw,
+    /// Both forks in one MacBinary III file, named with a ".bin" suffix
+    MacBinary,
+    /// Both forks in one AppleSingle file, named with a ".as" suffix
+    AppleSingle,
+    /// The data fork, with everything else in a `._` file next to it
+    AppleDouble,
+}
+
+impl ExportFormat {
+    /// Suffix added to host file names
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Self::MacBinary => ".bin",
+            Self::AppleSingle => ".as",
+            Self::Raw | Self::AppleDouble => "",
+        }
+    }
+    /// Contents of the host file and, for AppleDouble, of its `._` file. Files without a
+    /// resource fork or Finder information get no `._` file.
+    pub fn encode(self, file: &File) -> (Vec<u8>, Option<Vec<u8>>) {
+        match self {
+            Self::Raw => (file.data.clone(), None),
+            Self::MacBinary => (macbinary::encode(file), None),
+            Self::AppleSingle => (appledouble::encode_single(file), None),
+            Self::AppleDouble => {
+                let plain = file.rsrc.is_empty() && file.finder_info.iter().all(|b| *b == 0);
+                (file.data.clone(), (!plain).then(|| appledouble::encode_double(file)))
+            },
+        }
+    }
+}
+
+/// Makes a volume name safe to use as a host file name.
+///
+/// Slashes become colons, which HFS names cannot contain. Control characters, '%' and dots that
+/// would make the name special are escaped as `%XX`.
+pub fn host_name(name: &str) -> String {
+    let special_dots = name.chars().all(|c| c == '.') || name.starts_with("._");
+    let mut ret = String::with_capacity(name.len());
+    for (i, c) in name.chars().enumerate() {
+        match c {
+            '/' => ret.push(':'),
+            '.' if i == 0 && special_dots => ret.push_str("%2E"),
+            '%' => ret.push_str("%25"),
+            c if c.is_control() && (c as u32) < 0x100 => ret.push_str(&format!("%{:02X}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Reverses `host_name`
+pub fn from_host_name(name: &str) -> String {
+    let mut ret = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        let escaped = rest.strip_prefix('%')
+            .and_then(|r| r.get(..2))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (c, escaped) {
+            ('%', Some(b)) => {
+                ret.push(b as char);
+                rest = &rest[3..];
+                continue;
+            },
+            (':', _) => ret.push('/'),
+            (c, _) => ret.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    ret
+}
+
+/// Writes `file` into the host directory `dir`, returning the name of the written file
+pub fn write_file(dir: &Path, file: &File, format: ExportFormat) -> Result<String, HfsError> {
+    let name = host_name(&file.name) + format.suffix();
+    let (contents, sidecar) = format.encode(file);
+    let path = dir.join(&name);
+    fs::write(&path, contents)?;
+    if let Some(sidecar) = sidecar {
+        fs::write(dir.join(format!("._{}", name)), sidecar)?;
+    }
+    fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(system_time(file.modify_date))?;
+    Ok(name)
+}
+
+/// Copies a directory of a volume with everything below it into the host directory `dest`,
+/// which is created if missing
+pub fn extract<R: Read + Seek>(volume: &mut Volume<R>, dir_id: u32, dest: &Path, format: ExportFormat) -> Result<(), HfsError> {
+    fs::create_dir_all(dest)?;
+    for entry in volume.read_dir(dir_id)? {
+        match &entry.kind {
+            EntryKind::Directory(dir) => extract(volume, dir.id, &dest.join(host_name(&entry.name)), format)?,
+            EntryKind::File(_) => {
+                write_file(dest, &volume.read_file(&entry)?, format)?;
+            },
+        }
+    }
+    Ok(())
+}
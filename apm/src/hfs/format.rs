@@ -0,0 +1,415 @@
+//! Creation of HFS volumes
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use deku::prelude::*;
+
+use super::{
+    compare_keys, DirRecord, ExtDescriptor, ExtDataRec, FileRecord, HfsError, MasterDirectoryBlock,
+    RECORD_DIR, RECORD_FILE, ROOT_ID, ROOT_PARENT_ID,
+};
+use crate::{btree, macroman};
+
+const RECORD_DIR_THREAD: u8 = 3;
+const FIRST_USER_ID: u32 = 16;
+/// Maximum length of a file or directory name
+const MAX_NAME: usize = 31;
+/// Maximum length of a volume name
+const MAX_VOLUME_NAME: usize = 27;
+/// Number of 512-byte blocks before the volume bitmap: boot blocks and the MDB
+const BITMAP_START: u16 = 3;
+
+/// Seconds between 1904, the Mac OS epoch, and 1970
+const MAC_EPOCH_OFFSET: u64 = 2_082_844_800;
+
+/// Converts a time to the number of seconds since 1904 used by HFS
+pub fn mac_time(t: SystemTime) -> u32 {
+    let unix = t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    (unix + MAC_EPOCH_OFFSET).min(u32::MAX as u64) as u32
+}
+
+/// Converts a Mac OS date to a host time
+pub fn system_time(date: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs((date as u64).saturating_sub(MAC_EPOCH_OFFSET))
+}
+
+/// A file to be stored on a new volume
+#[derive(Clone, Debug, Default)]
+pub struct File {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub rsrc: Vec<u8>,
+    /// FInfo followed by FXInfo
+    pub finder_info: [u8; 32],
+    pub create_date: u32,
+    pub modify_date: u32,
+}
+
+/// A directory to be stored on a new volume
+#[derive(Clone, Debug, Default)]
+pub struct Folder {
+    pub name: String,
+    /// DInfo followed by DXInfo
+    pub finder_info: [u8; 32],
+    pub create_date: u32,
+    pub modify_date: u32,
+    pub files: Vec<File>,
+    pub folders: Vec<Folder>,
+}
+
+impl Folder {
+    /// Number of files and folders below this one
+    fn counts(&self) -> (u32, u32) {
+        self.folders.iter()
+            .map(Folder::counts)
+            .fold((self.files.len() as u32, self.folders.len() as u32), |a, b| (a.0 + b.0, a.1 + b.1))
+    }
+}
+
+fn encode_name(name: &str, max: usize) -> Result<Vec<u8>, HfsError> {
+    // ':' separates path components on Mac OS, '/' is its usual replacement on other systems
+    let ret = macroman::encode(&name.replace(':', "/"))
+        .ok_or_else(|| HfsError::InvalidName(name.to_string()))?;
+    if ret.is_empty() || ret.len() > max {
+        return Err(HfsError::InvalidName(name.to_string()));
+    }
+    Ok(ret)
+}
+
+fn pascal<const LEN: usize>(s: &[u8]) -> [u8; LEN] {
+    let mut ret = [0; LEN];
+    ret[0] = s.len() as u8;
+    ret[1..][..s.len()].copy_from_slice(s);
+    ret
+}
+
+/// Leaf record with a catalog key, padded so that the data starts on an even offset
+fn catalog_record(parent: u32, name: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut ret = vec![6 + name.len() as u8, 0];
+    ret.extend_from_slice(&parent.to_be_bytes());
+    ret.push(name.len() as u8);
+    ret.extend_from_slice(name);
+    if ret.len() % 2 == 1 {
+        ret.push(0);
+    }
+    ret.extend_from_slice(data);
+    ret
+}
+
+/// Index record key for the leaf record, catalog index keys always have the maximum length
+fn catalog_index_record(record: &[u8]) -> Vec<u8> {
+    let mut ret = vec![0u8; 38];
+    let len = record[0] as usize;
+    ret[0] = 0x25;
+    ret[1..][..len].copy_from_slice(&record[1..][..len]);
+    ret
+}
+
+fn thread_record(id: u32, parent: u32, name: &[u8]) -> Vec<u8> {
+    let mut data = vec![RECORD_DIR_THREAD, 0];
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(&parent.to_be_bytes());
+    data.extend_from_slice(&pascal::<32>(name));
+    catalog_record(id, &[], &data)
+}
+
+/// Layout of allocation blocks on a volume
+struct Allocator {
+    block_size: u32,
+    blocks: u16,
+    next: u32,
+}
+
+impl Allocator {
+    fn alloc(&mut self, bytes: u64) -> Result<ExtDataRec, HfsError> {
+        let count = bytes.div_ceil(self.block_size as u64) as u32;
+        if count == 0 {
+            return Ok(ExtDataRec::default());
+        }
+        if self.next + count > self.blocks as u32 {
+            return Err(HfsError::TooSmall);
+        }
+        let ext = ExtDescriptor { start: self.next as u16, count: count as u16 };
+        self.next += count;
+        Ok([ext, ExtDescriptor::default(), ExtDescriptor::default()])
+    }
+}
+
+struct Builder<'a> {
+    alloc: Allocator,
+    next_id: u32,
+    records: Vec<(u32, Vec<u8>, Vec<u8>)>,
+    /// Forks to copy into the volume, with their first allocation block
+    forks: Vec<(u16, &'a [u8])>,
+    /// Blessed System Folder and the System file inside it
+    system: Option<(u32, &'a File)>,
+}
+
+impl<'a> Builder<'a> {
+    fn add_folder(&mut self, folder: &'a Folder, id: u32, parent: u32, name: Vec<u8>) -> Result<(), HfsError> {
+        let dir = DirRecord {
+            kind: RECORD_DIR,
+            flags: 0,
+            valence: (folder.files.len() + folder.folders.len()) as u16,
+            id,
+            create_date: folder.create_date,
+            modify_date: folder.modify_date,
+            backup_date: 0,
+            user_info: folder.finder_info[..16].try_into().unwrap(),
+            finder_info: folder.finder_info[16..].try_into().unwrap(),
+            reserved: [0; 4],
+        };
+        self.records.push((id, Vec::new(), thread_record(id, parent, &name)));
+        self.records.push((parent, name.clone(), catalog_record(parent, &name, &dir.to_bytes()?)));
+
+        for file in folder.files.iter() {
+            let name = encode_name(&file.name, MAX_NAME)?;
+            let file_id = self.next_id;
+            self.next_id += 1;
+            let data_extents = self.alloc.alloc(file.data.len() as u64)?;
+            let rsrc_extents = self.alloc.alloc(file.rsrc.len() as u64)?;
+            self.forks.push((data_extents[0].start, &file.data));
+            self.forks.push((rsrc_extents[0].start, &file.rsrc));
+            let physical = |ext: &ExtDataRec| ext[0].count as u32 * self.alloc.block_size;
+            let rec = FileRecord {
+                kind: RECORD_FILE,
+                flags: 0,
+                file_type: 0,
+                user_info: file.finder_info[..16].try_into().unwrap(),
+                id: file_id,
+                data_start: 0,
+                data_logical: file.data.len() as u32,
+                data_physical: physical(&data_extents),
+                rsrc_start: 0,
+                rsrc_logical: file.rsrc.len() as u32,
+                rsrc_physical: physical(&rsrc_extents),
+                create_date: file.create_date,
+                modify_date: file.modify_date,
+                backup_date: 0,
+                finder_info: file.finder_info[16..].try_into().unwrap(),
+                clump_size: 0,
+                data_extents,
+                rsrc_extents,
+                reserved: 0,
+            };
+            if parent == ROOT_ID && file.name == "System" && self.system.is_none() {
+                // This folder is in the root directory and holds the System file
+                self.system = Some((id, file));
+            }
+            self.records.push((id, name.clone(), catalog_record(id, &name, &rec.to_bytes()?)));
+        }
+        for sub in folder.folders.iter() {
+            let name = encode_name(&sub.name, MAX_NAME)?;
+            let sub_id = self.next_id;
+            self.next_id += 1;
+            self.add_folder(sub, sub_id, id, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds resource `id` of type `ty` in a resource fork
+fn find_resource<'a>(fork: &'a [u8], ty: &[u8; 4], id: i16) -> Option<&'a [u8]> {
+    let be16 = |d: &[u8], off: usize| Some(u16::from_be_bytes(d.get(off..off + 2)?.try_into().ok()?));
+    let be32 = |d: &[u8], off: usize| Some(u32::from_be_bytes(d.get(off..off + 4)?.try_into().ok()?));
+    let data_off = be32(fork, 0)? as usize;
+    let map = fork.get(be32(fork, 4)? as usize..)?;
+    let types = map.get(be16(map, 24)? as usize..)?;
+    let type_count = be16(types, 0)?.wrapping_add(1) as usize;
+    for i in 0..type_count {
+        let entry = types.get(2 + i*8..2 + i*8 + 8)?;
+        if &entry[..4] != ty {
+            continue;
+        }
+        let count = be16(entry, 4)? as usize + 1;
+        let refs = types.get(be16(entry, 6)? as usize..)?;
+        for j in 0..count {
+            let r = refs.get(j*12..j*12 + 12)?;
+            if be16(r, 0)? as i16 != id {
+                continue;
+            }
+            let off = data_off + (be32(r, 4)? & 0xff_ffff) as usize;
+            let len = be32(fork, off)? as usize;
+            return fork.get(off + 4..off + 4 + len);
+        }
+    }
+    None
+}
+
+/// Writes an empty HFS volume named `name` filling `buf`
+pub fn format_blank(buf: &mut [u8], name: &str) -> Result<(), HfsError> {
+    format(buf, name, &Folder::default())
+}
+
+/// Writes a new HFS volume named `name` filling `buf`, holding the contents of `root`.
+///
+/// A folder in the root directory holding a "System" file is blessed, and the boot blocks are
+/// taken from the 'boot' resource of that file.
+pub fn format(buf: &mut [u8], name: &str, root: &Folder) -> Result<(), HfsError> {
+    let volume_name = encode_name(name, MAX_VOLUME_NAME)?;
+    let sectors = (buf.len()/512) as u64;
+    if sectors < 64 {
+        return Err(HfsError::TooSmall);
+    }
+
+    // Smallest allocation block size that keeps the block count within 16 bits
+    let mut block_size = 512u64;
+    let (blocks, bitmap_sectors) = loop {
+        let bitmap_sectors = (sectors/(block_size/512)).div_ceil(4096);
+        let avail = sectors - BITMAP_START as u64 - bitmap_sectors - 2;
+        let blocks = avail*512/block_size;
+        if blocks <= u16::MAX as u64 {
+            break (blocks as u16, bitmap_sectors);
+        }
+        block_size += 512;
+    };
+    let first_alloc_block = BITMAP_START as u64 + bitmap_sectors;
+
+    // Special files get about one percent of the volume each, like most formatters do, and
+    // more if their records need it
+    let tree_blocks = |min_nodes: u64| -> u64 {
+        let nodes = (sectors/128).max(min_nodes);
+        (nodes*512).div_ceil(block_size)
+    };
+    let mut builder = Builder {
+        alloc: Allocator { block_size: block_size as u32, blocks, next: 0 },
+        next_id: FIRST_USER_ID,
+        records: Vec::new(),
+        forks: Vec::new(),
+        system: None,
+    };
+    let extents_size = tree_blocks(4)*block_size;
+    let extents_extents = builder.alloc.alloc(extents_size)?;
+    let extents = btree::build(&[], |_| Vec::new(), 7, (extents_size/512) as u32)
+        .ok_or(HfsError::TooSmall)?;
+
+    // The catalog follows the files, once it is known how large it has to be
+    let (file_count, dir_count) = root.counts();
+    builder.add_folder(root, ROOT_ID, ROOT_PARENT_ID, volume_name.clone())?;
+    builder.records.sort_by(|a, b| compare_keys((a.0, &a.1), (b.0, &b.1)));
+    let records: Vec<Vec<u8>> = builder.records.iter().map(|(_, _, r)| r.clone()).collect();
+    let needed = btree::min_nodes(&records, catalog_index_record)
+        .ok_or(HfsError::TooManyFiles)?;
+    // Leaves room for files added later
+    let catalog_size = tree_blocks(needed as u64*5/4)*block_size;
+    let catalog_extents = builder.alloc.alloc(catalog_size)?;
+    let catalog = btree::build(&records, catalog_index_record, 0x25, (catalog_size/512) as u32)
+        .ok_or(HfsError::TooManyFiles)?;
+
+    buf.fill(0);
+    let alloc_offset = |start: u16| (first_alloc_block*512 + start as u64*block_size) as usize;
+    buf[alloc_offset(extents_extents[0].start)..][..extents.len()].copy_from_slice(&extents);
+    buf[alloc_offset(catalog_extents[0].start)..][..catalog.len()].copy_from_slice(&catalog);
+    for (start, fork) in builder.forks.iter().filter(|(_, f)| !f.is_empty()) {
+        buf[alloc_offset(*start)..][..fork.len()].copy_from_slice(fork);
+    }
+
+    let used = builder.alloc.next;
+    let bitmap = &mut buf[BITMAP_START as usize*512..][..bitmap_sectors as usize*512];
+    for n in 0..used as usize {
+        bitmap[n/8] |= 0x80 >> (n % 8);
+    }
+
+    let mut finder_info = [0u32; 8];
+    if let Some((folder, system)) = builder.system {
+        finder_info[0] = folder;
+        if let Some(boot) = find_resource(&system.rsrc, b"boot", 1) {
+            let len = boot.len().min(1024);
+            buf[..len].copy_from_slice(&boot[..len]);
+        }
+    }
+
+    let now = mac_time(SystemTime::now());
+    let mdb = MasterDirectoryBlock {
+        create_date: now,
+        modify_date: now,
+        // Cleanly unmounted
+        attributes: 0x0100,
+        root_files: root.files.len() as u16,
+        bitmap_start: BITMAP_START,
+        alloc_ptr: used as u16,
+        alloc_blocks: blocks,
+        alloc_block_size: block_size as u32,
+        clump_size: 4*block_size as u32,
+        first_alloc_block: first_alloc_block as u16,
+        next_cnid: builder.next_id,
+        free_blocks: blocks - used as u16,
+        name: pascal::<28>(&volume_name),
+        backup_date: 0,
+        seq_num: 0,
+        write_count: 0,
+        extents_clump: extents_size as u32,
+        catalog_clump: catalog_size as u32,
+        root_dirs: root.folders.len() as u16,
+        file_count,
+        dir_count,
+        finder_info,
+        embed_sig: 0,
+        embed_extent: ExtDescriptor::default(),
+        extents_size: extents_size as u32,
+        extents_extents,
+        catalog_size: catalog_size as u32,
+        catalog_extents,
+    };
+    let mdb = mdb.to_bytes()?;
+    buf[1024..][..mdb.len()].copy_from_slice(&mdb);
+    // Alternate MDB in the second to last block
+    let alt = (sectors as usize - 2)*512;
+    buf[alt..][..mdb.len()].copy_from_slice(&mdb);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hfs::{EntryKind, Fork, Volume};
+
+    fn file(name: &str, data: &[u8], rsrc: &[u8]) -> File {
+        File { name: name.to_string(), data: data.to_vec(), rsrc: rsrc.to_vec(), ..File::default() }
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let mut many = Folder { name: "Many".to_string(), ..Folder::default() };
+        many.files = (0..3000).map(|i| file(&format!("f{:04}", i), i.to_string().as_bytes(), &[])).collect();
+        let root = Folder {
+            name: "Disk".to_string(),
+            files: vec![file("Read Me", b"hello", b"resource fork"), file("Ärger", &[7; 5000], &[])],
+            folders: vec![many],
+            ..Folder::default()
+        };
+        let mut buf = vec![0; 8 << 20];
+        format(&mut buf, "Disk", &root).unwrap();
+
+        let mut volume = Volume::open(std::io::Cursor::new(&buf)).unwrap();
+        assert_eq!(volume.name(), "Disk");
+        let mut read = |path: &str, fork: Fork| match volume.lookup(path).unwrap().kind {
+            EntryKind::File(info) => volume.read_fork(&info, fork).unwrap(),
+            EntryKind::Directory(_) => panic!("{} is a directory", path),
+        };
+        assert_eq!(read("read me", Fork::Data), b"hello");
+        assert_eq!(read("Read Me", Fork::Resource), b"resource fork");
+        assert_eq!(read("ärger", Fork::Data), [7; 5000]);
+        assert_eq!(read("many/F2999", Fork::Data), b"2999");
+
+        let EntryKind::Directory(dir) = volume.lookup("Many").unwrap().kind else {
+            panic!("Many is not a directory");
+        };
+        let names: Vec<String> = volume.read_dir(dir.id).unwrap()
+            .into_iter()
+            .filter(|e| !e.is_dir() && e.parent == dir.id)
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names.len(), 3000);
+        assert!(names.windows(2).all(|w| w[0] < w[1]));
+        assert!(volume.lookup("Many/f3000").is_err());
+    }
+
+    #[test]
+    fn format_rejects_what_does_not_fit() {
+        let root = Folder { files: vec![file("Big", &[1; 100_000], &[])], ..Folder::default() };
+        let mut buf = vec![0; 64*1024];
+        assert!(matches!(format(&mut buf, "Small", &root), Err(HfsError::TooSmall)));
+    }
+}
@@ -0,0 +1,205 @@
+//! Growing volumes into space added to the end of their partitions
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use deku::prelude::*;
+
+use super::{plus, HfsError, MasterDirectoryBlock, VolumeHeader};
+
+fn read_at<V: Read + Seek>(v: &mut V, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut ret = vec![0; len];
+    v.seek(SeekFrom::Start(offset))?;
+    v.read_exact(&mut ret)?;
+    Ok(ret)
+}
+
+fn write_at<V: Write + Seek>(v: &mut V, offset: u64, data: &[u8]) -> io::Result<()> {
+    v.seek(SeekFrom::Start(offset))?;
+    v.write_all(data)
+}
+
+/// Byte ranges holding a volume bitmap, in order
+struct Bitmap(Vec<(u64, u64)>);
+
+impl Bitmap {
+    /// Number of allocation blocks the bitmap can describe
+    fn capacity(&self) -> u64 {
+        self.0.iter().map(|(_, len)| len*8).sum()
+    }
+    /// Marks allocation blocks `from..to` as used or free, returning how many of them changed
+    fn set<V: Read + Write + Seek>(&self, v: &mut V, from: u64, to: u64, used: bool) -> io::Result<u64> {
+        let mut changed = 0;
+        let mut base = 0;
+        for &(offset, len) in &self.0 {
+            let (lo, hi) = (from.max(base), to.min(base + len*8));
+            if lo < hi {
+                let first = (lo - base)/8;
+                let mut bytes = read_at(v, offset + first, ((hi - base).div_ceil(8) - first) as usize)?;
+                for bit in lo - base - first*8..hi - base - first*8 {
+                    let (byte, mask) = (&mut bytes[(bit/8) as usize], 0x80 >> (bit % 8));
+                    if (*byte & mask != 0) != used {
+                        *byte ^= mask;
+                        changed += 1;
+                    }
+                }
+                write_at(v, offset + first, &bytes)?;
+            }
+            base += len*8;
+        }
+        Ok(changed)
+    }
+}
+
+/// Copies `len` bytes at `from` to `to`, which may overlap them
+fn move_bytes<V: Read + Write + Seek>(v: &mut V, from: u64, to: u64, len: u64) -> io::Result<()> {
+    const CHUNK: u64 = 1 << 20;
+    let mut done = 0;
+    while done < len {
+        let n = CHUNK.min(len - done);
+        // Moving towards the end starts with the last chunk, so nothing is overwritten unread
+        let at = if to > from { len - done - n } else { done };
+        let data = read_at(v, from + at, n as usize)?;
+        write_at(v, to + at, &data)?;
+        done += n;
+    }
+    Ok(())
+}
+
+/// Grows the HFS, HFS+ or HFSX volume at the start of `volume` to fill all of it, after it was
+/// enlarged from `old_len` bytes. The added space is free, in HFS wrappers it goes to the
+/// embedded HFS+ volume instead.
+///
+/// The bitmap of an HFS volume grows into the start of the allocation blocks when it cannot
+/// describe the new ones, moving all of them towards the end. HFS volumes stop growing at 65535
+/// allocation blocks, which fails if they cannot grow at all.
+///
+/// Returns how many bytes at the end of `volume` stay unused because the volume reached the
+/// largest size its format allows, leftovers smaller than an allocation block are not counted.
+pub fn grow<V: Read + Write + Seek>(volume: &mut V, old_len: u64) -> Result<u64, HfsError> {
+    let new_len = volume.seek(SeekFrom::End(0))?;
+    if new_len < old_len || old_len < 1536 {
+        return Err(HfsError::CannotGrow("the partition is smaller than before"));
+    }
+    let header = read_at(volume, 1024, 512)?;
+    match &header[..2] {
+        b"BD" => grow_hfs(volume, MasterDirectoryBlock::from_bytes((&header, 0))?.1, old_len, new_len),
+        sig if sig == plus::SIGNATURE_PLUS || sig == plus::SIGNATURE_X => grow_plus(volume, 0, old_len, new_len),
+        _ => Err(HfsError::NotHfs),
+    }
+}
+
+fn grow_hfs<V: Read + Write + Seek>(v: &mut V, mut mdb: MasterDirectoryBlock, old_len: u64, new_len: u64) -> Result<u64, HfsError> {
+    let (old_first, block_size) = (mdb.first_alloc_block as u64, mdb.alloc_block_size as u64);
+    let bitmap_start = mdb.bitmap_start as u64;
+    if old_first <= bitmap_start || block_size == 0 || !block_size.is_multiple_of(512) {
+        return Err(HfsError::Corrupt("invalid volume layout"));
+    }
+    // The last two blocks hold the alternate MDB and are never allocated
+    let space = |first: u64| (new_len/512).saturating_sub(2).saturating_sub(first)*512/block_size;
+    // Each block of the bitmap describes 4096 allocation blocks
+    let mut first = old_first;
+    let blocks = loop {
+        let blocks = space(first).min(u16::MAX as u64);
+        let needed = bitmap_start + blocks.div_ceil(4096);
+        if needed <= first {
+            break blocks as u16;
+        }
+        first = needed;
+    };
+    let old = mdb.alloc_blocks;
+    if blocks <= old || first > u16::MAX as u64 {
+        return Err(HfsError::CannotGrow(if old == u16::MAX {
+            "the volume has the largest number of allocation blocks HFS allows"
+        } else {
+            "too little space was added"
+        }));
+    }
+    let added = blocks - old;
+    let embedded = mdb.embed_sig.to_be_bytes() == *plus::SIGNATURE_PLUS;
+    let embed = mdb.embed_extent;
+    if embedded && embed.start as u32 + embed.count as u32 != old as u32 {
+        return Err(HfsError::CannotGrow("the embedded volume does not end the wrapper"));
+    }
+
+    // The old alternate MDB is now in the middle of the volume, where data may move to
+    write_at(v, (old_len/512 - 2)*512, &[0; 512])?;
+    if first > old_first {
+        move_bytes(v, old_first*512, first*512, old as u64*block_size)?;
+        write_at(v, old_first*512, &vec![0; ((first - old_first)*512) as usize])?;
+    }
+    let bitmap = Bitmap(vec![(bitmap_start*512, (first - bitmap_start)*512)]);
+    if embedded {
+        bitmap.set(v, old as u64, blocks as u64, true)?;
+        mdb.embed_extent.count += added;
+    } else {
+        bitmap.set(v, old as u64, blocks as u64, false)?;
+        mdb.free_blocks += added;
+    }
+    mdb.alloc_blocks = blocks;
+    mdb.first_alloc_block = first as u16;
+    let mut unused = (space(first) - blocks as u64)*block_size;
+    if embedded {
+        let start = first*512 + embed.start as u64*block_size;
+        unused += grow_plus(v, start, embed.count as u64*block_size, mdb.embed_extent.count as u64*block_size)?;
+    }
+    let mdb = mdb.to_bytes()?;
+    write_at(v, 1024, &mdb)?;
+    write_at(v, (new_len/512 - 2)*512, &mdb)?;
+    Ok(unused)
+}
+
+/// Grows the HFS+ volume starting at byte `base` from `old_len` to `new_len` bytes, returning
+/// how many bytes it could not use, see `grow`
+fn grow_plus<V: Read + Write + Seek>(v: &mut V, base: u64, old_len: u64, new_len: u64) -> Result<u64, HfsError> {
+    let (_, mut vh) = VolumeHeader::from_bytes((&read_at(v, base + 1024, 512)?, 0))?;
+    let block_size = vh.block_size as u64;
+    if block_size == 0 || !block_size.is_multiple_of(512) {
+        return Err(HfsError::Corrupt("invalid allocation block size"));
+    }
+    let old_total = vh.total_blocks as u64;
+    let new_total = (new_len/block_size).min(u32::MAX as u64);
+    if new_total <= old_total {
+        return Err(HfsError::CannotGrow("too little space was added"));
+    }
+
+    let alloc = &mut vh.allocation_file;
+    if alloc.extents.iter().map(|e| e.count).sum::<u32>() != alloc.total_blocks {
+        return Err(HfsError::CannotGrow("the allocation file has overflow extents"));
+    }
+    let mut bitmap = Bitmap(alloc.extents.iter()
+        .filter(|e| e.count > 0)
+        .map(|e| (base + e.start as u64*block_size, e.count as u64*block_size))
+        .collect());
+    // The allocation file grows into the new space when it cannot describe all of it
+    let extra = new_total.saturating_sub(bitmap.capacity()).div_ceil(block_size*8);
+    let tail = |len: u64, total: u64| ((len - 1024)/block_size).min(total);
+    if old_total + extra >= tail(new_len, new_total) {
+        return Err(HfsError::CannotGrow("too little space was added"));
+    }
+    if extra > 0 {
+        let slot = alloc.extents.iter_mut()
+            .find(|e| e.count == 0)
+            .ok_or(HfsError::CannotGrow("the allocation file has no room for another extent"))?;
+        slot.start = old_total as u32;
+        slot.count = extra as u32;
+        alloc.total_blocks += extra as u32;
+        alloc.logical_size = alloc.total_blocks as u64*block_size;
+        bitmap.0.push((base + old_total*block_size, extra*block_size));
+        v.seek(SeekFrom::Start(base + old_total*block_size))?;
+        io::copy(&mut io::repeat(0).take(extra*block_size), v)?;
+    }
+
+    // Blocks holding the alternate volume header are always allocated
+    let mut free = vh.free_blocks as u64 + new_total - old_total;
+    bitmap.set(v, old_total, new_total, false)?;
+    free += bitmap.set(v, tail(old_len, old_total), old_total, false)?;
+    free -= bitmap.set(v, old_total, old_total + extra, true)?;
+    free -= bitmap.set(v, tail(new_len, new_total), new_total, true)?;
+    vh.free_blocks = free as u32;
+    vh.total_blocks = new_total as u32;
+
+    write_at(v, base + old_len - 1024, &[0; 512])?;
+    let vh = vh.to_bytes()?;
+    write_at(v, base + 1024, &vh)?;
+    write_at(v, base + new_len - 1024, &vh)?;
+    Ok((new_len/block_size - new_total)*block_size)
+}
@@ -0,0 +1,83 @@
+//! Reading of host directories into folders for new volumes
+
+use std::fs;
+use std::path::Path;
+
+use super::{from_host_name, mac_time, File, Folder, HfsError};
+use crate::{appledouble, macbinary};
+
+/// Name of the AppleDouble file carrying metadata of `name`
+fn sidecar(name: &str) -> String {
+    format!("._{}", name)
+}
+
+/// Reads a host directory with everything below it.
+///
+/// Resource forks and Finder information are taken from AppleDouble `._` files next to the
+/// files they describe. Files in MacBinary or AppleSingle format are unpacked, and get the name
+/// stored inside. Other names are unescaped with `from_host_name`.
+pub fn folder_from_dir(path: &Path) -> Result<Folder, HfsError> {
+    let meta = fs::metadata(path)?;
+    let mut ret = Folder {
+        name: path.file_name()
+            .map(|n| from_host_name(&n.to_string_lossy()))
+            .unwrap_or_default(),
+        create_date: meta.created().map(mac_time).unwrap_or(0),
+        modify_date: meta.modified().map(mac_time).unwrap_or(0),
+        ..Default::default()
+    };
+
+    let mut entries: Vec<_> = fs::read_dir(path)?
+        .collect::<Result<_, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let host = entry.file_name().to_string_lossy().into_owned();
+        if host.starts_with("._") || host == ".DS_Store" {
+            continue;
+        }
+        let name = from_host_name(&host);
+        let double = fs::read(path.join(sidecar(&host)))
+            .ok()
+            .and_then(|d| appledouble::decode(&d));
+
+        if entry.file_type()?.is_dir() {
+            let mut folder = folder_from_dir(&entry.path())?;
+            if let Some(double) = double {
+                folder.finder_info = double.finder_info;
+            }
+            ret.folders.push(folder);
+            continue;
+        }
+
+        let meta = entry.metadata()?;
+        let data = fs::read(entry.path())?;
+        let single = data.starts_with(&appledouble::APPLE_SINGLE_MAGIC.to_be_bytes())
+            .then(|| appledouble::decode(&data))
+            .flatten();
+        let file = match (double, macbinary::decode(&data), single) {
+            (Some(double), _, _) => File {
+                name,
+                data,
+                ..double
+            },
+            (None, Some(bin), _) => bin,
+            (None, None, Some(single)) => File {
+                name: if single.name.is_empty() {
+                    name.strip_suffix(".as").unwrap_or(&name).to_string()
+                } else {
+                    single.name.clone()
+                },
+                ..single
+            },
+            (None, None, None) => File {
+                name,
+                data,
+                create_date: meta.created().map(mac_time).unwrap_or(0),
+                modify_date: meta.modified().map(mac_time).unwrap_or(0),
+                ..Default::default()
+            },
+        };
+        ret.files.push(file);
+    }
+    Ok(ret)
+}
@@ -0,0 +1,590 @@
+//! Reading and creation of HFS volumes, as stored in `Apple_HFS` partitions. HFS+ and HFSX
+//! volumes, wrapped in HFS or not, can be read as well.
+
+use std::cmp::Ordering;
+use std::io::{Read, Seek, SeekFrom};
+use deku::prelude::*;
+use thiserror::Error;
+
+use crate::btree::{BTree, KeyLength};
+use crate::macroman;
+
+mod export;
+mod format;
+mod grow;
+mod import;
+mod plus;
+pub use export::{extract, from_host_name, host_name, write_file, ExportFormat};
+pub use format::{format, format_blank, mac_time, system_time, File, Folder};
+pub use grow::grow;
+pub use import::folder_from_dir;
+pub use plus::{ExtentDescriptor, ForkData, VolumeHeader};
+
+/// Catalog node ID of the parent of the root directory
+pub const ROOT_PARENT_ID: u32 = 1;
+/// Catalog node ID of the root directory
+pub const ROOT_ID: u32 = 2;
+const CATALOG_FILE_ID: u32 = 4;
+
+const RECORD_DIR: i8 = 1;
+const RECORD_FILE: i8 = 2;
+
+#[derive(Error, Debug)]
+pub enum HfsError {
+    #[error("Not an HFS volume")]
+    NotHfs,
+    #[error("Parse error")]
+    Deku(#[from] deku::DekuError),
+    #[error("Volume structure is damaged: {0}")]
+    Corrupt(&'static str),
+    #[error("'{0}' does not exist")]
+    NotFound(String),
+    #[error("'{0}' is not a directory")]
+    NotADirectory(String),
+    #[error("'{0}' is not a file")]
+    NotAFile(String),
+    #[error("'{0}' is not a valid HFS name")]
+    InvalidName(String),
+    #[error("Volume is too small for its contents")]
+    TooSmall,
+    #[error("Too many files and folders for the catalog")]
+    TooManyFiles,
+    #[error("Volume cannot grow: {0}")]
+    CannotGrow(&'static str),
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big", ctx = "_: deku::ctx::Endian")]
+pub struct ExtDescriptor {
+    /// First allocation block
+    pub start: u16,
+    /// Number of allocation blocks
+    pub count: u16,
+}
+
+pub type ExtDataRec = [ExtDescriptor; 3];
+
+/// Extents of a fork as `(first allocation block, number of blocks)`, for HFS and HFS+ alike
+pub(crate) type Extents = Vec<(u32, u32)>;
+
+/// Extents that did not fit in catalog records, as fork type, file ID, first allocation block
+/// of the fork they describe, and the extents themselves
+type Overflow = Vec<(u8, u32, u32, Extents)>;
+
+/// Splits a catalog key into its parent ID and name
+type KeyParser = fn(&[u8]) -> Option<(u32, &[u8])>;
+
+/// Master directory block, describing the whole volume
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "big", magic = b"BD")]
+pub struct MasterDirectoryBlock {
+    pub(crate) create_date: u32,
+    pub(crate) modify_date: u32,
+    pub(crate) attributes: u16,
+    /// Number of files in the root directory
+    pub(crate) root_files: u16,
+    /// First 512-byte block of the volume bitmap
+    pub(crate) bitmap_start: u16,
+    /// Where to start looking for free allocation blocks
+    pub(crate) alloc_ptr: u16,
+    /// Number of allocation blocks
+    pub(crate) alloc_blocks: u16,
+    /// Size of an allocation block in bytes
+    pub(crate) alloc_block_size: u32,
+    pub(crate) clump_size: u32,
+    /// First 512-byte block of the first allocation block
+    pub(crate) first_alloc_block: u16,
+    pub(crate) next_cnid: u32,
+    pub(crate) free_blocks: u16,
+    /// Volume name as a Pascal string
+    pub(crate) name: [u8; 28],
+    pub(crate) backup_date: u32,
+    pub(crate) seq_num: u16,
+    pub(crate) write_count: u32,
+    pub(crate) extents_clump: u32,
+    pub(crate) catalog_clump: u32,
+    /// Number of directories in the root directory
+    pub(crate) root_dirs: u16,
+    pub(crate) file_count: u32,
+    pub(crate) dir_count: u32,
+    pub(crate) finder_info: [u32; 8],
+    /// Signature of an embedded volume, "H+" for HFS+ wrapped in HFS
+    pub(crate) embed_sig: u16,
+    pub(crate) embed_extent: ExtDescriptor,
+    pub(crate) extents_size: u32,
+    pub(crate) extents_extents: ExtDataRec,
+    pub(crate) catalog_size: u32,
+    pub(crate) catalog_extents: ExtDataRec,
+}
+
+impl MasterDirectoryBlock {
+    pub fn name(&self) -> String {
+        let len = (self.name[0] as usize).min(27);
+        macroman::decode(&self.name[1..][..len])
+    }
+    pub fn alloc_blocks(&self) -> u16 { self.alloc_blocks }
+    pub fn alloc_block_size(&self) -> u32 { self.alloc_block_size }
+    pub fn free_blocks(&self) -> u16 { self.free_blocks }
+    pub fn file_count(&self) -> u32 { self.file_count }
+    pub fn dir_count(&self) -> u32 { self.dir_count }
+    pub fn create_date(&self) -> u32 { self.create_date }
+    pub fn modify_date(&self) -> u32 { self.modify_date }
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct DirRecord {
+    pub(crate) kind: i8,
+    #[deku(pad_bytes_before = "1")]
+    pub(crate) flags: u16,
+    /// Number of entries in the directory
+    pub(crate) valence: u16,
+    pub(crate) id: u32,
+    pub(crate) create_date: u32,
+    pub(crate) modify_date: u32,
+    pub(crate) backup_date: u32,
+    pub(crate) user_info: [u8; 16],
+    pub(crate) finder_info: [u8; 16],
+    pub(crate) reserved: [u32; 4],
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub(crate) struct FileRecord {
+    pub(crate) kind: i8,
+    #[deku(pad_bytes_before = "1")]
+    pub(crate) flags: u8,
+    pub(crate) file_type: i8,
+    /// Finder information: type, creator, flags, location and folder
+    pub(crate) user_info: [u8; 16],
+    pub(crate) id: u32,
+    pub(crate) data_start: u16,
+    pub(crate) data_logical: u32,
+    pub(crate) data_physical: u32,
+    pub(crate) rsrc_start: u16,
+    pub(crate) rsrc_logical: u32,
+    pub(crate) rsrc_physical: u32,
+    pub(crate) create_date: u32,
+    pub(crate) modify_date: u32,
+    pub(crate) backup_date: u32,
+    pub(crate) finder_info: [u8; 16],
+    pub(crate) clump_size: u16,
+    pub(crate) data_extents: ExtDataRec,
+    pub(crate) rsrc_extents: ExtDataRec,
+    pub(crate) reserved: u32,
+}
+
+/// The two forks every file has
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fork {
+    Data,
+    Resource,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirInfo {
+    pub id: u32,
+    /// Number of entries in the directory
+    pub valence: u32,
+    pub create_date: u32,
+    pub modify_date: u32,
+    /// DInfo followed by DXInfo
+    pub finder_info: [u8; 32],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    pub id: u32,
+    pub file_type: [u8; 4],
+    pub creator: [u8; 4],
+    pub finder_flags: u16,
+    pub locked: bool,
+    pub create_date: u32,
+    pub modify_date: u32,
+    /// FInfo followed by FXInfo
+    pub finder_info: [u8; 32],
+    pub data_size: u64,
+    pub rsrc_size: u64,
+    pub(crate) data_extents: Extents,
+    pub(crate) rsrc_extents: Extents,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    Directory(DirInfo),
+    File(FileInfo),
+}
+
+/// A file or directory in the catalog
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Name converted to Unicode
+    pub name: String,
+    /// Name as stored on the volume, in MacRoman for HFS and big endian UTF-16 for HFS+
+    pub raw_name: Vec<u8>,
+    pub parent: u32,
+    pub kind: EntryKind,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        matches!(self.kind, EntryKind::Directory(_))
+    }
+}
+
+/// Parses an extent record at the start of `rec`, which must be at least 12 bytes long
+fn parse_ext_rec(rec: &[u8]) -> ExtDataRec {
+    let be16 = |off: usize| u16::from_be_bytes([rec[off], rec[off + 1]]);
+    [0, 1, 2].map(|i| ExtDescriptor { start: be16(i*4), count: be16(i*4 + 2) })
+}
+
+fn ext_rec_extents(rec: &ExtDataRec) -> Extents {
+    rec.iter().map(|e| (e.start as u32, e.count as u32)).collect()
+}
+
+/// A catalog key: parent ID and name
+fn parse_catalog_key(key: &[u8]) -> Option<(u32, &[u8])> {
+    let parent = u32::from_be_bytes(key.get(1..5)?.try_into().ok()?);
+    let len = *key.get(5)? as usize;
+    Some((parent, key.get(6..6 + len)?))
+}
+
+/// Orders catalog keys
+pub(crate) fn compare_keys(a: (u32, &[u8]), b: (u32, &[u8])) -> Ordering {
+    a.0.cmp(&b.0)
+        .then_with(|| macroman::compare(a.1, b.1))
+}
+
+/// Parses the extents overflow file of an HFS volume
+fn parse_overflow(tree: &BTree) -> Overflow {
+    let mut ret = Vec::new();
+    for (key, rec) in tree.leaf_records() {
+        if key.len() < 7 || rec.len() < 12 {
+            continue;
+        }
+        let ext = ext_rec_extents(&parse_ext_rec(rec));
+        let fnum = u32::from_be_bytes(key[1..5].try_into().unwrap());
+        let fabn = u16::from_be_bytes(key[5..7].try_into().unwrap());
+        ret.push((key[0], fnum, fabn as u32, ext));
+    }
+    ret.sort_by_key(|(ty, fnum, fabn, _)| (*ty, *fnum, *fabn));
+    ret
+}
+
+/// Parses the extents overflow file of an HFS+ volume
+fn parse_overflow_plus(tree: &BTree) -> Overflow {
+    let mut ret = Vec::new();
+    for (key, rec) in tree.leaf_records() {
+        let Some(ext) = plus::parse_ext_rec(rec) else {
+            continue;
+        };
+        if key.len() < 10 {
+            continue;
+        }
+        let fnum = u32::from_be_bytes(key[2..6].try_into().unwrap());
+        let fabn = u32::from_be_bytes(key[6..10].try_into().unwrap());
+        ret.push((key[0], fnum, fabn, ext));
+    }
+    ret.sort_by_key(|(ty, fnum, fabn, _)| (*ty, *fnum, *fabn));
+    ret
+}
+
+/// Where a volume and its allocation blocks are
+#[derive(Clone, Copy)]
+struct Geometry {
+    /// Offset of the volume in the data it is read from, in bytes
+    base: u64,
+    /// Length of the volume in bytes
+    len: u64,
+    /// Offset of the first allocation block in bytes
+    first_block: u64,
+    block_size: u64,
+}
+
+/// Offset in the data a volume is read from and length of an allocation block range, both in
+/// bytes
+fn alloc_range(geometry: Geometry, start: u32, count: u32) -> Result<(u64, u64), HfsError> {
+    let offset = geometry.first_block + start as u64*geometry.block_size;
+    let len = count as u64*geometry.block_size;
+    if offset + len > geometry.len {
+        return Err(HfsError::Corrupt("extent outside of the volume"));
+    }
+    Ok((geometry.base + offset, len))
+}
+
+/// Reads `size` bytes of data stored in the given extents
+fn read_extents<R: Read + Seek>(source: &mut R, geometry: Geometry, extents: &[(u32, u32)], size: u64) -> Result<Vec<u8>, HfsError> {
+    let covered: u64 = extents.iter().map(|(_, count)| *count as u64*geometry.block_size).sum();
+    if covered < size {
+        return Err(HfsError::Corrupt("fork is shorter than its length"));
+    }
+    let mut ret = Vec::with_capacity(size.min(geometry.len) as usize);
+    for (start, count) in extents.iter().filter(|(_, count)| *count > 0) {
+        let left = size - ret.len() as u64;
+        if left == 0 {
+            break;
+        }
+        let (offset, len) = alloc_range(geometry, *start, *count)?;
+        source.seek(SeekFrom::Start(offset))?;
+        let read = source.by_ref().take(len.min(left)).read_to_end(&mut ret)?;
+        if (read as u64) < len.min(left) {
+            return Err(HfsError::Corrupt("extent outside of the volume"));
+        }
+    }
+    Ok(ret)
+}
+
+/// All extents of a fork, starting with `first` from its catalog record and continuing with
+/// extents from the extents overflow file
+fn fork_extents(overflow: &Overflow, id: u32, fork: Fork, first: &[(u32, u32)]) -> Extents {
+    let ty = match fork {
+        Fork::Data => 0x00,
+        Fork::Resource => 0xff,
+    };
+    let mut ret = first.to_vec();
+    for (_, _, _, ext) in overflow.iter().filter(|(t, fnum, _, _)| *t == ty && *fnum == id) {
+        ret.extend_from_slice(ext);
+    }
+    ret
+}
+
+/// The header of a volume
+#[derive(Clone, Debug)]
+pub enum Header {
+    Hfs(MasterDirectoryBlock),
+    Plus(Box<VolumeHeader>),
+}
+
+/// An HFS, HFS+ or HFSX volume, read piece by piece. Only its catalog and extents overflow file
+/// are kept in memory.
+pub struct Volume<R> {
+    source: R,
+    header: Header,
+    geometry: Geometry,
+    catalog: BTree,
+    overflow: Overflow,
+}
+
+impl<R: Read + Seek> Volume<R> {
+    /// Opens the volume at the start of `source`. An HFS+ volume wrapped in HFS is opened instead
+    /// of its wrapper.
+    pub fn open(mut source: R) -> Result<Self, HfsError> {
+        let len = source.seek(SeekFrom::End(0))?;
+        if len < 1536 {
+            return Err(HfsError::NotHfs);
+        }
+        let mut header = [0; 512];
+        source.seek(SeekFrom::Start(1024))?;
+        source.read_exact(&mut header)?;
+        match &header[..2] {
+            b"BD" => (),
+            sig if sig == plus::SIGNATURE_PLUS || sig == plus::SIGNATURE_X => return Self::open_plus(source, 0, len),
+            _ => return Err(HfsError::NotHfs),
+        }
+        let (_, mdb) = MasterDirectoryBlock::from_bytes((&header[..], 0))?;
+        let geometry = Geometry {
+            base: 0,
+            len,
+            first_block: mdb.first_alloc_block as u64*512,
+            block_size: mdb.alloc_block_size as u64,
+        };
+        if mdb.embed_sig.to_be_bytes() == *plus::SIGNATURE_PLUS {
+            let (base, len) = alloc_range(geometry, mdb.embed_extent.start as u32, mdb.embed_extent.count as u32)?;
+            return Self::open_plus(source, base, len);
+        }
+
+        let extents = read_extents(&mut source, geometry, &ext_rec_extents(&mdb.extents_extents), mdb.extents_size as u64)?;
+        let extents = BTree::new(extents, KeyLength::Byte)
+            .ok_or(HfsError::Corrupt("invalid extents overflow file"))?;
+        let overflow = parse_overflow(&extents);
+
+        let catalog_extents = fork_extents(&overflow, CATALOG_FILE_ID, Fork::Data, &ext_rec_extents(&mdb.catalog_extents));
+        let catalog = read_extents(&mut source, geometry, &catalog_extents, mdb.catalog_size as u64)?;
+        let catalog = BTree::new(catalog, KeyLength::Byte)
+            .ok_or(HfsError::Corrupt("invalid catalog file"))?;
+        Ok(Self { source, header: Header::Hfs(mdb), geometry, catalog, overflow })
+    }
+    /// Opens the HFS+ volume of `len` bytes starting at byte `base` of `source`
+    fn open_plus(mut source: R, base: u64, len: u64) -> Result<Self, HfsError> {
+        if len < 1536 {
+            return Err(HfsError::NotHfs);
+        }
+        let mut header = [0; 512];
+        source.seek(SeekFrom::Start(base + 1024))?;
+        source.read_exact(&mut header)?;
+        let (_, vh) = VolumeHeader::from_bytes((&header[..], 0))?;
+        let geometry = Geometry { base, len, first_block: 0, block_size: vh.block_size as u64 };
+
+        let extents_file = &vh.extents_file;
+        let extents = read_extents(&mut source, geometry, &extents_file.extents(), extents_file.logical_size)?;
+        let extents = BTree::new(extents, KeyLength::Word)
+            .ok_or(HfsError::Corrupt("invalid extents overflow file"))?;
+        let overflow = parse_overflow_plus(&extents);
+
+        let catalog_file = &vh.catalog_file;
+        let catalog_extents = fork_extents(&overflow, CATALOG_FILE_ID, Fork::Data, &catalog_file.extents());
+        let catalog = read_extents(&mut source, geometry, &catalog_extents, catalog_file.logical_size)?;
+        let catalog = BTree::new(catalog, KeyLength::Word)
+            .ok_or(HfsError::Corrupt("invalid catalog file"))?;
+        Ok(Self { source, header: Header::Plus(Box::new(vh)), geometry, catalog, overflow })
+    }
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+    /// The master directory block of an HFS volume
+    pub fn mdb(&self) -> Option<&MasterDirectoryBlock> {
+        match &self.header {
+            Header::Hfs(mdb) => Some(mdb),
+            Header::Plus(_) => None,
+        }
+    }
+    pub fn is_plus(&self) -> bool {
+        matches!(self.header, Header::Plus(_))
+    }
+    pub fn name(&self) -> String {
+        match &self.header {
+            Header::Hfs(mdb) => mdb.name(),
+            // HFS+ keeps the volume name only as the name of the root directory
+            Header::Plus(_) => self.root().map(|e| e.name).unwrap_or_default(),
+        }
+    }
+    /// Lists the contents of a directory
+    pub fn read_dir(&self, dir_id: u32) -> Result<Vec<Entry>, HfsError> {
+        let plus = self.is_plus();
+        let parse_key: KeyParser = if plus {
+            plus::parse_catalog_key
+        } else {
+            parse_catalog_key
+        };
+        let leaf = self.catalog.find_leaf(|key| match parse_key(key) {
+            Some(key) if plus => plus::compare_parent(key, dir_id),
+            Some(key) => compare_keys(key, (dir_id, &[])),
+            None => Ordering::Less,
+        });
+        let Some(leaf) = leaf else {
+            return Ok(Vec::new());
+        };
+        let mut ret = Vec::new();
+        for (key, rec) in self.catalog.leaf_records_from(leaf) {
+            let Some((parent, name)) = parse_key(key) else {
+                continue;
+            };
+            match parent.cmp(&dir_id) {
+                Ordering::Less => continue,
+                Ordering::Greater => break,
+                Ordering::Equal => (),
+            }
+            let kind = if plus {
+                plus::parse_record(rec)?
+            } else {
+                Self::parse_record(rec)?
+            };
+            if let Some(kind) = kind {
+                ret.push(Entry {
+                    name: if plus { plus::decode_name(name) } else { macroman::decode(name) },
+                    raw_name: name.to_vec(),
+                    parent,
+                    kind,
+                });
+            }
+        }
+        Ok(ret)
+    }
+    fn parse_record(rec: &[u8]) -> Result<Option<EntryKind>, HfsError> {
+        Ok(match rec.first().map(|t| *t as i8) {
+            Some(RECORD_DIR) => {
+                let (_, dir) = DirRecord::from_bytes((rec, 0))?;
+                let mut finder_info = [0; 32];
+                finder_info[..16].copy_from_slice(&dir.user_info);
+                finder_info[16..].copy_from_slice(&dir.finder_info);
+                Some(EntryKind::Directory(DirInfo {
+                    id: dir.id,
+                    valence: dir.valence as u32,
+                    create_date: dir.create_date,
+                    modify_date: dir.modify_date,
+                    finder_info,
+                }))
+            },
+            Some(RECORD_FILE) => {
+                let (_, file) = FileRecord::from_bytes((rec, 0))?;
+                Some(EntryKind::File(Self::file_info(&file)))
+            },
+            _ => None,
+        })
+    }
+    fn file_info(file: &FileRecord) -> FileInfo {
+        let mut finder_info = [0; 32];
+        finder_info[..16].copy_from_slice(&file.user_info);
+        finder_info[16..].copy_from_slice(&file.finder_info);
+        FileInfo {
+            id: file.id,
+            file_type: file.user_info[0..4].try_into().unwrap(),
+            creator: file.user_info[4..8].try_into().unwrap(),
+            finder_flags: u16::from_be_bytes([file.user_info[8], file.user_info[9]]),
+            locked: file.flags & 0x01 != 0,
+            create_date: file.create_date,
+            modify_date: file.modify_date,
+            finder_info,
+            data_size: file.data_logical as u64,
+            rsrc_size: file.rsrc_logical as u64,
+            data_extents: ext_rec_extents(&file.data_extents),
+            rsrc_extents: ext_rec_extents(&file.rsrc_extents),
+        }
+    }
+    /// The root directory
+    pub fn root(&self) -> Result<Entry, HfsError> {
+        self.read_dir(ROOT_PARENT_ID)?
+            .into_iter()
+            .find(|e| matches!(&e.kind, EntryKind::Directory(d) if d.id == ROOT_ID))
+            .ok_or(HfsError::Corrupt("no root directory"))
+    }
+    /// Whether `component` of a path names `entry`
+    fn name_matches(&self, entry: &Entry, component: &str) -> bool {
+        match &self.header {
+            Header::Hfs(_) => macroman::encode(component)
+                .is_some_and(|raw| macroman::eq_ignore_case(&entry.raw_name, &raw)),
+            Header::Plus(vh) if vh.is_hfsx() && self.catalog.key_compare_type() == plus::COMPARE_BINARY =>
+                entry.name == component,
+            Header::Plus(_) => entry.name.to_lowercase() == component.to_lowercase(),
+        }
+    }
+    /// Finds an entry by its path, with components separated by '/'
+    pub fn lookup(&self, path: &str) -> Result<Entry, HfsError> {
+        let mut cur = self.root()?;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            let EntryKind::Directory(dir) = &cur.kind else {
+                return Err(HfsError::NotADirectory(cur.name));
+            };
+            cur = self.read_dir(dir.id)?
+                .into_iter()
+                .find(|e| self.name_matches(e, component))
+                .ok_or_else(|| HfsError::NotFound(path.to_string()))?;
+        }
+        Ok(cur)
+    }
+    /// Reads both forks and the Finder information of a file
+    pub fn read_file(&mut self, entry: &Entry) -> Result<File, HfsError> {
+        let EntryKind::File(info) = &entry.kind else {
+            return Err(HfsError::NotAFile(entry.name.clone()));
+        };
+        Ok(File {
+            name: entry.name.clone(),
+            data: self.read_fork(info, Fork::Data)?,
+            rsrc: self.read_fork(info, Fork::Resource)?,
+            finder_info: info.finder_info,
+            create_date: info.create_date,
+            modify_date: info.modify_date,
+        })
+    }
+    /// Reads a whole fork of a file
+    pub fn read_fork(&mut self, file: &FileInfo, fork: Fork) -> Result<Vec<u8>, HfsError> {
+        let (first, size) = match fork {
+            Fork::Data => (&file.data_extents, file.data_size),
+            Fork::Resource => (&file.rsrc_extents, file.rsrc_size),
+        };
+        let extents = fork_extents(&self.overflow, file.id, fork, first);
+        read_extents(&mut self.source, self.geometry, &extents, size)
+    }
+}
@@ -0,0 +1,281 @@
+//! On-disk structures of HFS+ and HFSX volumes
+
+use std::cmp::Ordering;
+use deku::prelude::*;
+
+use super::{DirInfo, EntryKind, Extents, FileInfo, HfsError};
+
+pub(crate) const SIGNATURE_PLUS: &[u8; 2] = b"H+";
+pub(crate) const SIGNATURE_X: &[u8; 2] = b"HX";
+
+const RECORD_FOLDER: i16 = 1;
+const RECORD_FILE: i16 = 2;
+
+/// Key comparison of an HFSX catalog that tells names apart by case
+pub(crate) const COMPARE_BINARY: u8 = 0xbc;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big", ctx = "_: deku::ctx::Endian")]
+pub struct ExtentDescriptor {
+    /// First allocation block
+    pub start: u32,
+    /// Number of allocation blocks
+    pub count: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "big", ctx = "_: deku::ctx::Endian")]
+pub struct ForkData {
+    /// Length of the fork in bytes
+    pub logical_size: u64,
+    pub clump_size: u32,
+    pub total_blocks: u32,
+    pub extents: [ExtentDescriptor; 8],
+}
+
+impl ForkData {
+    pub(crate) fn extents(&self) -> Extents {
+        self.extents.iter().map(|e| (e.start, e.count)).collect()
+    }
+}
+
+/// Volume header of an HFS+ or HFSX volume, stored where HFS keeps its MDB
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "big")]
+pub struct VolumeHeader {
+    /// "H+" or "HX"
+    pub(crate) signature: [u8; 2],
+    pub(crate) version: u16,
+    pub(crate) attributes: u32,
+    pub(crate) last_mounted_version: u32,
+    pub(crate) journal_info_block: u32,
+    pub(crate) create_date: u32,
+    pub(crate) modify_date: u32,
+    pub(crate) backup_date: u32,
+    pub(crate) checked_date: u32,
+    pub(crate) file_count: u32,
+    pub(crate) folder_count: u32,
+    /// Size of an allocation block in bytes
+    pub(crate) block_size: u32,
+    /// Number of allocation blocks
+    pub(crate) total_blocks: u32,
+    pub(crate) free_blocks: u32,
+    pub(crate) next_allocation: u32,
+    pub(crate) rsrc_clump_size: u32,
+    pub(crate) data_clump_size: u32,
+    pub(crate) next_cnid: u32,
+    pub(crate) write_count: u32,
+    pub(crate) encodings_bitmap: u64,
+    pub(crate) finder_info: [u32; 8],
+    pub(crate) allocation_file: ForkData,
+    pub(crate) extents_file: ForkData,
+    pub(crate) catalog_file: ForkData,
+    pub(crate) attributes_file: ForkData,
+    pub(crate) startup_file: ForkData,
+}
+
+impl VolumeHeader {
+    pub fn is_hfsx(&self) -> bool { &self.signature == SIGNATURE_X }
+    pub fn block_size(&self) -> u32 { self.block_size }
+    pub fn total_blocks(&self) -> u32 { self.total_blocks }
+    pub fn free_blocks(&self) -> u32 { self.free_blocks }
+    pub fn file_count(&self) -> u32 { self.file_count }
+    pub fn folder_count(&self) -> u32 { self.folder_count }
+    pub fn create_date(&self) -> u32 { self.create_date }
+    pub fn modify_date(&self) -> u32 { self.modify_date }
+}
+
+fn be16(d: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(d.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn be32(d: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(d.get(off..off + 4)?.try_into().ok()?))
+}
+
+/// Parses an extent record of eight descriptors at the start of `rec`
+pub(crate) fn parse_ext_rec(rec: &[u8]) -> Option<Extents> {
+    (0..8).map(|i| Some((be32(rec, i*8)?, be32(rec, i*8 + 4)?))).collect()
+}
+
+/// A catalog key: parent ID and the name as stored, in big endian UTF-16
+pub(crate) fn parse_catalog_key(key: &[u8]) -> Option<(u32, &[u8])> {
+    let parent = be32(key, 0)?;
+    let len = be16(key, 4)? as usize;
+    Some((parent, key.get(6..6 + len*2)?))
+}
+
+/// Orders catalog keys by parent only, with names ordered after the empty one
+pub(crate) fn compare_parent(key: (u32, &[u8]), parent: u32) -> Ordering {
+    key.0.cmp(&parent)
+        .then(if key.1.is_empty() { Ordering::Equal } else { Ordering::Greater })
+}
+
+pub(crate) fn decode_name(raw: &[u8]) -> String {
+    let units: Vec<u16> = raw.chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+pub(crate) fn parse_record(rec: &[u8]) -> Result<Option<EntryKind>, HfsError> {
+    let corrupt = || HfsError::Corrupt("catalog record too short");
+    let finder_info = || -> Option<[u8; 32]> { rec.get(48..80)?.try_into().ok() };
+    Ok(match be16(rec, 0).map(|t| t as i16) {
+        Some(RECORD_FOLDER) => {
+            let parse = || Some(DirInfo {
+                id: be32(rec, 8)?,
+                valence: be32(rec, 4)?,
+                create_date: be32(rec, 12)?,
+                modify_date: be32(rec, 16)?,
+                finder_info: finder_info()?,
+            });
+            Some(EntryKind::Directory(parse().ok_or_else(corrupt)?))
+        },
+        Some(RECORD_FILE) => {
+            let fork = |off: usize| -> Option<(u64, Extents)> {
+                let size = u64::from_be_bytes(rec.get(off..off + 8)?.try_into().ok()?);
+                Some((size, parse_ext_rec(rec.get(off + 16..)?)?))
+            };
+            let parse = || {
+                let finder_info = finder_info()?;
+                let (data, rsrc) = (fork(88)?, fork(168)?);
+                Some(FileInfo {
+                    id: be32(rec, 8)?,
+                    file_type: finder_info[0..4].try_into().ok()?,
+                    creator: finder_info[4..8].try_into().ok()?,
+                    finder_flags: be16(&finder_info, 8)?,
+                    locked: be16(rec, 2)? & 0x01 != 0,
+                    create_date: be32(rec, 12)?,
+                    modify_date: be32(rec, 16)?,
+                    finder_info,
+                    data_size: data.0,
+                    rsrc_size: rsrc.0,
+                    data_extents: data.1,
+                    rsrc_extents: rsrc.1,
+                })
+            };
+            Some(EntryKind::File(parse().ok_or_else(corrupt)?))
+        },
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+    use crate::btree;
+    use crate::hfs::{Fork, Volume};
+
+    fn key(parent: u32, name: &str) -> Vec<u8> {
+        let name: Vec<u16> = name.encode_utf16().collect();
+        let mut ret = ((6 + 2*name.len()) as u16).to_be_bytes().to_vec();
+        ret.extend_from_slice(&parent.to_be_bytes());
+        ret.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        ret.extend(name.iter().flat_map(|u| u.to_be_bytes()));
+        ret
+    }
+
+    /// Writes a fork of `size` bytes in the given extents at the start of `d`
+    fn fork(d: &mut [u8], size: u64, extents: &[(u32, u32)]) {
+        d[..8].copy_from_slice(&size.to_be_bytes());
+        let blocks: u32 = extents.iter().map(|e| e.1).sum();
+        d[12..16].copy_from_slice(&blocks.to_be_bytes());
+        for (i, (start, count)) in extents.iter().enumerate() {
+            d[16 + i*8..][..4].copy_from_slice(&start.to_be_bytes());
+            d[20 + i*8..][..4].copy_from_slice(&count.to_be_bytes());
+        }
+    }
+
+    fn folder(parent: u32, name: &str, id: u32) -> Vec<u8> {
+        let mut rec = vec![0; 88];
+        rec[..2].copy_from_slice(&RECORD_FOLDER.to_be_bytes());
+        rec[8..12].copy_from_slice(&id.to_be_bytes());
+        [key(parent, name), rec].concat()
+    }
+
+    fn file(parent: u32, name: &str, id: u32, size: u64, extents: &[(u32, u32)]) -> Vec<u8> {
+        let mut rec = vec![0; 248];
+        rec[..2].copy_from_slice(&RECORD_FILE.to_be_bytes());
+        rec[8..12].copy_from_slice(&id.to_be_bytes());
+        rec[48..56].copy_from_slice(b"TEXTttxt");
+        fork(&mut rec[88..], size, extents);
+        [key(parent, name), rec].concat()
+    }
+
+    fn index_record(rec: &[u8]) -> Vec<u8> {
+        rec[..2 + u16::from_be_bytes([rec[0], rec[1]]) as usize].to_vec()
+    }
+
+    /// An HFS+ volume of 64 blocks of 512 bytes. "Big" has its data in two extents of its
+    /// catalog record and one of the extents overflow file.
+    fn volume(files: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec![0; 64*512];
+        let mut overflow = vec![0, 10, 0, 0];
+        overflow.extend_from_slice(&20u32.to_be_bytes());
+        overflow.extend_from_slice(&3u32.to_be_bytes());
+        let mut extents = vec![0; 64];
+        extents[..8].copy_from_slice(&[0, 0, 0, 40, 0, 0, 0, 2]);
+        overflow.extend_from_slice(&extents);
+        let extents = btree::build(&[overflow], index_record, 10, 2).unwrap();
+        buf[4*512..][..extents.len()].copy_from_slice(&extents);
+
+        let mut records = vec![folder(1, "Disk", 2)];
+        records.extend_from_slice(files);
+        let catalog = btree::build(&records, index_record, 516, 8).unwrap();
+        buf[8*512..][..catalog.len()].copy_from_slice(&catalog);
+
+        let vh = &mut buf[1024..1536];
+        vh[..4].copy_from_slice(b"H+\0\x04");
+        vh[40..44].copy_from_slice(&512u32.to_be_bytes());
+        vh[44..48].copy_from_slice(&64u32.to_be_bytes());
+        fork(&mut vh[192..], 2*512, &[(4, 2)]);
+        fork(&mut vh[272..], 8*512, &[(8, 8)]);
+        for (i, block) in (0..5).zip([20, 21, 30, 40, 41]) {
+            buf[block*512..][..512].fill(i as u8 + 1);
+        }
+        buf[50*512..][..5].copy_from_slice(b"hello");
+        buf
+    }
+
+    fn read(buf: &[u8], path: &str) -> Result<Vec<u8>, HfsError> {
+        let mut volume = Volume::open(Cursor::new(buf))?;
+        match volume.lookup(path)?.kind {
+            EntryKind::File(info) => volume.read_fork(&info, Fork::Data),
+            EntryKind::Directory(_) => panic!("{} is a directory", path),
+        }
+    }
+
+    #[test]
+    fn read_catalog_and_overflow_extents() {
+        let buf = volume(&[
+            file(2, "Big", 20, 5*512 - 100, &[(20, 2), (30, 1)]),
+            file(2, "Read Me", 21, 5, &[(50, 1)]),
+        ]);
+        let volume = Volume::open(Cursor::new(&buf)).unwrap();
+        assert!(volume.is_plus());
+        assert_eq!(volume.name(), "Disk");
+        let names: Vec<String> = volume.read_dir(2).unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["Big", "Read Me"]);
+
+        assert_eq!(read(&buf, "read me").unwrap(), b"hello");
+        let big = read(&buf, "Big").unwrap();
+        let expected: Vec<u8> = (1..=5u8).flat_map(|i| [i; 512]).take(5*512 - 100).collect();
+        assert_eq!(big, expected);
+    }
+
+    #[test]
+    fn reject_corrupt_fork_sizes() {
+        // Longer than its extents
+        let buf = volume(&[file(2, "Read Me", 21, u64::MAX, &[(50, 1)])]);
+        assert!(matches!(read(&buf, "Read Me"), Err(HfsError::Corrupt(_))));
+        // Extents past the end of the volume
+        let buf = volume(&[file(2, "Read Me", 21, 1 << 40, &[(50, u32::MAX)])]);
+        assert!(matches!(read(&buf, "Read Me"), Err(HfsError::Corrupt(_))));
+        // A catalog longer than its extents
+        let mut buf = volume(&[]);
+        buf[1024 + 272..][..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(Volume::open(Cursor::new(&buf)), Err(HfsError::Corrupt(_))));
+    }
+}
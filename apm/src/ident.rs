@@ -0,0 +1,165 @@
+//! Identification of device drivers found on a disk
+
+use std::fmt;
+
+use crate::{ApmMap, DriverType, PartitionMap, apple_checksum};
+
+/// Fingerprints of released drivers known without a database, in the format read by
+/// [`KnownDriver::parse_db`]
+const BUILTIN_DB: &str = include_str!("drivers.tsv");
+
+/// A driver with a known fingerprint
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnownDriver {
+    /// SHA-1 of the driver code, as lowercase hex
+    pub sha1: String,
+    pub vendor: String,
+    pub product: String,
+    pub version: String,
+    pub cpu: String,
+}
+
+impl KnownDriver {
+    /// Parses a fingerprint database, one driver per line as tab-separated
+    /// `sha1 vendor product version cpu`. Empty lines and lines starting with '#' are ignored.
+    pub fn parse_db(db: &str) -> Vec<KnownDriver> {
+        db.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| {
+                let mut fields = l.split('\t').map(str::trim);
+                Some(KnownDriver {
+                    sha1: fields.next()?.to_ascii_lowercase(),
+                    vendor: fields.next()?.to_string(),
+                    product: fields.next()?.to_string(),
+                    version: fields.next().unwrap_or("").to_string(),
+                    cpu: fields.next().unwrap_or("").to_string(),
+                })
+            })
+            .collect()
+    }
+    /// Fingerprints of released drivers that are built in
+    pub fn builtin() -> Vec<KnownDriver> {
+        Self::parse_db(BUILTIN_DB)
+    }
+}
+
+/// Outcome of matching a driver against known drivers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Match<'a> {
+    /// The fingerprint matches a known driver exactly
+    Known(&'a KnownDriver),
+    Unknown,
+}
+
+/// Where a driver was found and what it was identified as
+#[derive(Clone, Debug)]
+pub struct DriverIdent<'a> {
+    /// Index in the driver descriptor map
+    pub driver: Option<usize>,
+    /// Index of the partition holding the driver
+    pub partition: Option<usize>,
+    pub system_type: Option<DriverType>,
+    /// Processor type of the partition holding the driver
+    pub proc_type: Option<String>,
+    /// Length of the code covered by the fingerprint, in bytes
+    pub len: usize,
+    pub sha1: String,
+    /// Whether the checksum stored in the partition map matches the code, if there is one
+    pub checksum_ok: Option<bool>,
+    pub matched: Match<'a>,
+}
+
+impl DriverIdent<'_> {
+    /// Whether the driver differs from the code its map entry describes
+    pub fn is_modified(&self) -> bool {
+        self.checksum_ok == Some(false)
+    }
+}
+
+impl fmt::Display for Match<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Match::Known(k) => write!(f, "{} {} {} ({})", k.vendor, k.product, k.version, k.cpu),
+            Match::Unknown => write!(f, "Unknown driver"),
+        }
+    }
+}
+
+/// Matches driver code against a fingerprint database
+pub fn identify<'a>(code: &[u8], db: &'a [KnownDriver]) -> (String, Match<'a>) {
+    let sha1 = sha1_smol::Sha1::from(code).digest().to_string();
+    match db.iter().find(|k| k.sha1 == sha1) {
+        Some(known) => (sha1, Match::Known(known)),
+        None => (sha1, Match::Unknown),
+    }
+}
+
+/// Identifies every driver referenced by the DDM and every `Apple_Driver*` partition
+pub fn identify_drivers<'a>(map: &ApmMap, db: &'a [KnownDriver]) -> Vec<DriverIdent<'a>> {
+    let mut ret = Vec::new();
+    let mut seen = Vec::new();
+    for (i, driver) in map.drivers().enumerate() {
+        let partition = map.driver_partition(i);
+        seen.extend(partition);
+        let (code, entry) = match partition.and_then(|idx| map.partition_entry(idx).map(|e| (idx, e))) {
+            Some((idx, entry)) if entry.boot_size() > 0 => {
+                let mut data = map.partition_data(idx).unwrap_or_default();
+                let len = (entry.boot_size() as usize).min(data.len());
+                data.to_mut().truncate(len);
+                (data, Some(entry))
+            },
+            _ => (map.driver_bytes(i).unwrap_or_default(), None),
+        };
+        let (sha1, matched) = identify(&code, db);
+        ret.push(DriverIdent {
+            driver: Some(i),
+            partition,
+            system_type: Some(driver.driver_type()),
+            proc_type: entry.map(|e| e.proc_type().to_string()),
+            len: code.len(),
+            sha1,
+            checksum_ok: entry.map(|e| e.boot_checksum() == apple_checksum(&code) as u32),
+            matched,
+        });
+    }
+    for (idx, entry) in map.partitions().enumerate() {
+        if seen.contains(&idx) || !entry.part_type().starts_with("Apple_Driver") {
+            continue;
+        }
+        let data = map.partition_data(idx).unwrap_or_default();
+        let code = match entry.boot_size() as usize {
+            0 => &data[..],
+            len => &data[..len.min(data.len())],
+        };
+        let (sha1, matched) = identify(code, db);
+        ret.push(DriverIdent {
+            driver: None,
+            partition: Some(idx),
+            system_type: None,
+            proc_type: Some(entry.proc_type().to_string()),
+            len: code.len(),
+            sha1,
+            checksum_ok: (entry.boot_size() > 0).then(|| entry.boot_checksum() == apple_checksum(code) as u32),
+            matched,
+        });
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identify_matches_whole_code_only() {
+        let code = b"\0\0driver code\0".to_vec();
+        let (sha1, matched) = identify(&code, &[]);
+        assert_eq!(matched, Match::Unknown);
+
+        let db = KnownDriver::parse_db(&format!("# comment\n\n{}\tApple\tHD SC Setup\t7.3.5\t68000", sha1.to_uppercase()));
+        assert_eq!(identify(&code, &db).1, Match::Known(&db[0]));
+        assert_eq!(identify(&code, &db).1.to_string(), "Apple HD SC Setup 7.3.5 (68000)");
+        assert_eq!(identify(&code[1..], &db).1, Match::Unknown);
+    }
+}
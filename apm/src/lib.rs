@@ -1,8 +1,33 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
 use derivative::Derivative;
 use deku::prelude::*;
 use thiserror::Error;
 
+use device::Device;
+
+pub mod appledouble;
+mod btree;
+mod builder;
+pub mod detect;
+pub mod device;
+pub mod hfs;
+pub mod ident;
+pub mod macbinary;
+pub mod macroman;
+pub mod scan;
+mod stream;
+mod view;
+
+pub use builder::{ApmBuilder, BootInfo, DriverSpec, PartitionSpec, Source};
+pub use stream::{PartitionReader, PartitionWriter};
+pub use view::{ApmMapRef, PartitionMap};
+
 #[derive(Clone, Debug, DekuRead, DekuWrite)]
 #[deku(endian = "big", magic = b"ER")]
 pub struct DriverDescriptorBlock {
@@ -28,6 +53,13 @@ impl DriverDescriptorBlock {
         self.drivers.push(data);
         self.driver_count += 1;
     }
+    pub fn remove_driver_data(&mut self, num: usize) -> Option<DriverData> {
+        if num >= self.drivers.len() {
+            return None;
+        }
+        self.driver_count -= 1;
+        Some(self.drivers.remove(num))
+    }
     pub fn blk_count(&self) -> u32 {
         self.blk_count
     }
@@ -65,9 +97,113 @@ pub struct DriverData {
     system_type: u16,
 }
 
+/// Known values of the operating system or processor field of a driver descriptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DriverType {
+    /// Classic Mac OS SCSI driver for 68k machines
+    MacOs68k,
+    /// A/UX driver
+    AUX,
+    /// ProDOS driver
+    ProDos,
+    /// Mac OS ATA driver
+    MacOsAta,
+    /// Mac OS ATA driver chained for PowerPC machines
+    MacOsPpc,
+    /// Mac OS SCSI driver chained for newer machines
+    MacOsChained,
+    Other(u16),
+}
+
+impl DriverType {
+    const NAMES: [(DriverType, &'static str, &'static str); 6] = [
+        (DriverType::MacOs68k, "mac68k", "Mac OS 68k"),
+        (DriverType::AUX, "aux", "A/UX"),
+        (DriverType::ProDos, "prodos", "ProDOS"),
+        (DriverType::MacOsAta, "mac-ata", "Mac OS ATA"),
+        (DriverType::MacOsPpc, "ppc", "Mac OS PowerPC"),
+        (DriverType::MacOsChained, "mac-chained", "Mac OS chained"),
+    ];
+    /// Short name accepted by `from_str`
+    pub fn short_name(&self) -> Option<&'static str> {
+        Self::NAMES.iter()
+            .find(|(ty, _, _)| ty == self)
+            .map(|(_, short, _)| *short)
+    }
+    /// Type of the partitions Apple's tools keep drivers of this type in
+    pub fn partition_type(&self) -> &'static str {
+        match self {
+            Self::MacOsAta => "Apple_Driver_ATA",
+            Self::AUX | Self::ProDos => "Apple_Driver",
+            _ => "Apple_Driver43",
+        }
+    }
+    /// Processor type in the map entries of partitions holding drivers of this type
+    pub fn proc_type(&self) -> &'static str {
+        match self {
+            Self::MacOsPpc => "PowerPC",
+            Self::ProDos => "6502",
+            _ => "68000",
+        }
+    }
+}
+
+impl From<u16> for DriverType {
+    fn from(v: u16) -> Self {
+        match v {
+            0x0001 => Self::MacOs68k,
+            0x0002 => Self::AUX,
+            0x0003 => Self::ProDos,
+            0x0701 => Self::MacOsAta,
+            0xf8ff => Self::MacOsPpc,
+            0xffff => Self::MacOsChained,
+            v => Self::Other(v),
+        }
+    }
+}
+
+impl From<DriverType> for u16 {
+    fn from(v: DriverType) -> u16 {
+        match v {
+            DriverType::MacOs68k => 0x0001,
+            DriverType::AUX => 0x0002,
+            DriverType::ProDos => 0x0003,
+            DriverType::MacOsAta => 0x0701,
+            DriverType::MacOsPpc => 0xf8ff,
+            DriverType::MacOsChained => 0xffff,
+            DriverType::Other(v) => v,
+        }
+    }
+}
+
+impl fmt::Display for DriverType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match Self::NAMES.iter().find(|(ty, _, _)| ty == self) {
+            Some((_, _, name)) => write!(f, "{} (0x{:04x})", name, u16::from(*self)),
+            None => write!(f, "Unknown (0x{:04x})", u16::from(*self)),
+        }
+    }
+}
+
+impl FromStr for DriverType {
+    type Err = ApmError;
+    /// Accepts a short name like "mac68k" or "ppc", or a decimal or 0x-prefixed number
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((ty, _, _)) = Self::NAMES.iter().find(|(_, short, _)| short.eq_ignore_ascii_case(s)) {
+            return Ok(*ty);
+        }
+        let num = match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+        num.map(DriverType::from)
+            .map_err(|_| ApmError::UnknownDriverType(s.to_string()))
+    }
+}
+
 impl DriverData {
-    pub fn new(start: u32, size: u16, system_type: u16) -> Self {
-        Self { start, size, system_type }
+    pub fn new(start: u32, size: u16, system_type: impl Into<u16>) -> Self {
+        Self { start, size, system_type: system_type.into() }
     }
     pub fn start(&self) -> u32 {
         self.start
@@ -78,6 +214,27 @@ impl DriverData {
     pub fn ty(&self) -> u16 {
         self.system_type
     }
+    pub fn driver_type(&self) -> DriverType {
+        DriverType::from(self.system_type)
+    }
+}
+
+/// Bits of the partition status field
+pub mod status {
+    pub const VALID: u32 = 0x1;
+    pub const ALLOCATED: u32 = 0x2;
+    pub const IN_USE: u32 = 0x4;
+    pub const BOOTABLE: u32 = 0x8;
+    pub const READABLE: u32 = 0x10;
+    pub const WRITABLE: u32 = 0x20;
+    pub const POSITION_INDEPENDENT: u32 = 0x40;
+    pub const OS_SPECIFIC: u32 = 0x80;
+    pub const CHAINABLE: u32 = 0x100;
+    pub const REAL_DRIVER: u32 = 0x200;
+
+    /// Status of partitions holding a device driver
+    pub const DRIVER: u32 = VALID | ALLOCATED | IN_USE | BOOTABLE | READABLE | WRITABLE
+        | POSITION_INDEPENDENT | CHAINABLE | REAL_DRIVER;
 }
 
 #[derive(Clone, Debug, DekuRead, DekuWrite)]
@@ -110,7 +267,7 @@ pub struct PartitionEntry {
     status: u32,
     /// Start of boot code in blocks
     boot_start: u32,
-    /// Size of boot code in blocks
+    /// Size of boot code in bytes
     boot_size: u32,
     /// Load address of boot code
     boot_load_address: u32,
@@ -131,15 +288,32 @@ impl PartitionEntry {
     fn read_string<const LEN: usize, R: io::Read>(reader: &mut deku::reader::Reader<R>) -> Result<String, DekuError> {
         let mut buf = [0u8; LEN];
         reader.read_bytes(LEN, &mut buf)?;
-        Ok(buf.into_iter().take_while(|v| *v != 0).map(|c| c as char).collect::<String>())
+        let len = buf.iter().position(|v| *v == 0).unwrap_or(LEN);
+        Ok(macroman::decode(&buf[..len]))
     }
     fn write_string<const LEN: usize, R: io::Write>(writer: &mut deku::writer::Writer<R>, val: &str) -> Result<(), DekuError> {
+        let bytes = Self::encode_string(val, LEN)
+            .map_err(|e| DekuError::InvalidParam(e.to_string().into()))?;
         let mut ret = [0u8; LEN];
-        for (i, ch) in val.chars().enumerate() {
-            ret[i] = ch as u8;
-        }
+        ret[..bytes.len()].copy_from_slice(&bytes);
         writer.write_bytes(&ret)
     }
+    /// Encodes a string field of at most `len` bytes as MacRoman
+    fn encode_string(val: &str, len: usize) -> Result<Vec<u8>, ApmError> {
+        let bytes = macroman::encode(val)
+            .ok_or_else(|| ApmError::NotMacRoman(val.to_string()))?;
+        if bytes.len() > len {
+            return Err(ApmError::StringTooLong(val.to_string(), len));
+        }
+        Ok(bytes)
+    }
+    /// Checks that the name, type and processor type can be stored in the entry
+    pub fn validate(&self) -> Result<(), ApmError> {
+        Self::encode_string(&self.name, 32)?;
+        Self::encode_string(&self.ty, 32)?;
+        Self::encode_string(&self.proc_type, 16)?;
+        Ok(())
+    }
     pub fn new() -> Self {
         Self {
             sig: 0x504d,
@@ -217,8 +391,8 @@ impl PartitionEntry {
     pub fn set_partition_count(&mut self, cnt: u32) {
         self.partition_count = cnt;
     }
+    /// Length in blocks. Foreign maps may give a different `data_size`, which is not checked.
     pub fn length(&self) -> u32 {
-        assert_eq!(self.data_count, self.length);
         self.length
     }
     pub fn with_length(mut self, len: u32) -> Self {
@@ -242,12 +416,295 @@ impl PartitionEntry {
     }
 }
 
+impl Default for PartitionEntry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Error, Debug, Clone)]
 pub enum ApmError {
     #[error("Parse/Encode error")]
     Deku(#[from] deku::DekuError),
     #[error("Failed to locate a sufficiently sized empty space")]
     NoSpace,
+    #[error("Blocks {0}..{1} lie outside of the device")]
+    OutOfBounds(u32, u32),
+    #[error("Blocks {0}..{1} overlap an existing partition")]
+    Overlap(u32, u32),
+    #[error("Partition map has no room for another entry")]
+    MapFull,
+    #[error("{0} bytes of data do not fit in {1} blocks")]
+    DataTooLarge(usize, u32),
+    #[error("{0} bytes of data do not fill {1} blocks")]
+    DataTooSmall(usize, u32),
+    #[error("Partitions must be at least one block long")]
+    EmptyPartition,
+    #[error("Partition {0} does not exist")]
+    NoPartition(usize),
+    #[error("Driver {0} does not exist")]
+    NoDriver(usize),
+    #[error("Unknown driver type '{0}'")]
+    UnknownDriverType(String),
+    #[error("'{0}' is longer than {1} bytes")]
+    StringTooLong(String, usize),
+    #[error("'{0}' contains characters missing from MacRoman")]
+    NotMacRoman(String),
+    #[error("Block {0} is not a multiple of {1}")]
+    Misaligned(u32, u32),
+    #[error("'{0}' is mounted or in use ({1})")]
+    Mounted(String, String),
+    #[error("The undo journal '{0}' is damaged")]
+    BadJournal(String),
+    #[error("I/O error")]
+    Io(#[source] Arc<io::Error>),
+    #[error("HFS error")]
+    Hfs(#[source] Arc<hfs::HfsError>),
+}
+
+impl From<io::Error> for ApmError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(Arc::new(e))
+    }
+}
+
+impl From<hfs::HfsError> for ApmError {
+    fn from(e: hfs::HfsError) -> Self {
+        Self::Hfs(Arc::new(e))
+    }
+}
+
+/// How to handle data whose size differs from the partition it is written to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fit {
+    /// Data must be exactly as long as the partition
+    #[default]
+    Exact,
+    /// Shorter data is followed by zeroes
+    Pad,
+    /// Longer data is cut at the end of the partition
+    Truncate,
+}
+
+/// Which of the unused areas of a device gets picked for new data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// The first area that is large enough
+    #[default]
+    FirstFit,
+    /// The smallest area that is large enough
+    BestFit,
+    /// The last area that is large enough, filled from its end
+    EndOfDisk,
+}
+
+/// Describes how space for new partitions and drivers is allocated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocPolicy {
+    strategy: Strategy,
+    /// Alignment of the first block, in blocks
+    align: u32,
+}
+
+impl AllocPolicy {
+    pub fn new() -> Self {
+        Self { strategy: Strategy::FirstFit, align: 1 }
+    }
+    pub fn strategy(&self) -> Strategy { self.strategy }
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+    pub fn align(&self) -> u32 { self.align }
+    /// Aligns the start of new data to a multiple of `blocks`, 0 and 1 disable alignment
+    pub fn with_align(mut self, blocks: u32) -> Self {
+        self.align = blocks.max(1);
+        self
+    }
+}
+
+impl AllocPolicy {
+    /// Picks the first block for `size` blocks of data from the unused areas in `holes`
+    pub(crate) fn place(&self, holes: &[(u32, u32)], size: u32) -> Result<u32, ApmError> {
+        let align = self.align as u64;
+        let fitting = holes.iter()
+            .filter_map(|&(start, end)| {
+                let (start, end) = (start as u64, end as u64);
+                let first = start.div_ceil(align) * align;
+                let last = end.checked_sub(size as u64)? / align * align;
+                (first <= last).then_some((start, end, first as u32, last as u32))
+            });
+
+        let found = match self.strategy {
+            Strategy::FirstFit => fitting.map(|(_, _, first, _)| first).next(),
+            Strategy::BestFit => fitting.min_by_key(|(start, end, _, _)| end - start)
+                .map(|(_, _, first, _)| first),
+            Strategy::EndOfDisk => fitting.map(|(_, _, _, last)| last).next_back(),
+        };
+        found.ok_or(ApmError::NoSpace)
+    }
+}
+
+/// Lists the areas of a device of `total` blocks that none of the `used` ranges cover
+pub(crate) fn holes_between(mut used: Vec<(u32, u32)>, total: u32) -> Vec<(u32, u32)> {
+    used.sort_unstable();
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    for (start, end) in used {
+        if start > pos && pos < total {
+            ret.push((pos, start.min(total)));
+        }
+        pos = pos.max(end);
+    }
+    if pos < total {
+        ret.push((pos, total));
+    }
+    ret
+}
+
+impl Default for AllocPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Blocks copied at once by `ApmMap::flush`
+const FLUSH_CHUNK: u32 = 2048;
+
+/// New contents of blocks of a device, not written to it yet
+#[derive(Clone)]
+enum Patch {
+    /// Whole blocks of data
+    Data(Vec<u8>),
+    /// The given number of zeroed blocks
+    Zero(u32),
+}
+
+impl Patch {
+    fn blocks(&self) -> u32 {
+        match self {
+            Self::Data(data) => (data.len()/512) as u32,
+            Self::Zero(blocks) => *blocks,
+        }
+    }
+    /// Blocks `from..to` of the patch
+    fn slice(&self, from: u32, to: u32) -> Self {
+        match self {
+            Self::Data(data) => Self::Data(data[from as usize*512..to as usize*512].to_vec()),
+            Self::Zero(_) => Self::Zero(to - from),
+        }
+    }
+}
+
+/// Where the blocks of a map's device are kept
+#[derive(Clone)]
+enum Storage {
+    /// The whole image, read into memory
+    Memory(Vec<u8>),
+    /// An image or device read as needed, with changes kept by their first block until flushed
+    /// and the number of blocks, which only differs from the device's after `ApmMap::resize_device`
+    Device(Arc<Device>, BTreeMap<u32, Patch>, u32),
+}
+
+impl Storage {
+    fn blocks(&self) -> u32 {
+        match self {
+            Self::Memory(data) => (data.len()/512) as u32,
+            Self::Device(_, _, blocks) => *blocks,
+        }
+    }
+    /// Changes the number of blocks, dropping changes past the new end. Added blocks read as
+    /// zeroes, unless the device already holds them.
+    fn resize(&mut self, blocks: u32) {
+        match self {
+            Self::Memory(data) => data.resize(blocks as usize*512, 0),
+            Self::Device(_, patches, len) => {
+                *len = blocks;
+                patches.retain(|start, _| *start < blocks);
+                if let Some(mut last) = patches.last_entry() {
+                    let start = *last.key();
+                    if start + last.get().blocks() > blocks {
+                        let cut = last.get().slice(0, blocks - start);
+                        last.insert(cut);
+                    }
+                }
+            },
+        }
+    }
+    /// Contents of blocks `start..end`, which must lie within the device
+    fn read(&self, start: u32, end: u32) -> io::Result<Cow<'_, [u8]>> {
+        if let Self::Memory(data) = self {
+            return Ok(Cow::Borrowed(&data[start as usize*512..end as usize*512]));
+        }
+        let mut ret = vec![0; (end - start) as usize*512];
+        self.read_at(start as u64*512, &mut ret)?;
+        Ok(Cow::Owned(ret))
+    }
+    /// Fills `buf` with the bytes at `offset`, which must lie within the device
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let (device, patches) = match self {
+            Self::Memory(data) => {
+                buf.copy_from_slice(&data[offset as usize..][..buf.len()]);
+                return Ok(());
+            },
+            Self::Device(device, patches, _) => (device, patches),
+        };
+        // Blocks past the end of the device were added by a resize
+        let on_device = device.size().saturating_sub(offset).min(buf.len() as u64) as usize;
+        device.read_at(offset, &mut buf[..on_device])?;
+        buf[on_device..].fill(0);
+        let end = offset + buf.len() as u64;
+        for (&p_start, patch) in patches.range(..end.div_ceil(512) as u32) {
+            let p_offset = p_start as u64*512;
+            let (from, to) = (p_offset.max(offset), (p_offset + patch.blocks() as u64*512).min(end));
+            if from >= to {
+                continue;
+            }
+            let region = &mut buf[(from - offset) as usize..(to - offset) as usize];
+            match patch {
+                Patch::Data(data) => region.copy_from_slice(&data[(from - p_offset) as usize..][..region.len()]),
+                Patch::Zero(_) => region.fill(0),
+            }
+        }
+        Ok(())
+    }
+    /// Puts `data` at the start of block `start`, zeroing everything after it up to block `end`
+    fn write(&mut self, start: u32, data: &[u8], end: u32) {
+        let patches = match self {
+            Self::Memory(raw) => {
+                let region = &mut raw[start as usize*512..end as usize*512];
+                region[..data.len()].copy_from_slice(data);
+                region[data.len()..].fill(0);
+                return;
+            },
+            Self::Device(_, patches, _) => patches,
+        };
+        let data_end = start + data.len().div_ceil(512) as u32;
+        let mut blocks = data.to_vec();
+        blocks.resize((data_end - start) as usize*512, 0);
+        for (p_start, patch) in [(start, Patch::Data(blocks)), (data_end, Patch::Zero(end - data_end))] {
+            let p_end = p_start + patch.blocks();
+            if p_start == p_end {
+                continue;
+            }
+            // Older patches are cut down to what the new one leaves of them
+            let overlapping: Vec<u32> = patches.range(..p_end)
+                .filter(|(s, old)| *s + old.blocks() > p_start)
+                .map(|(s, _)| *s)
+                .collect();
+            for s in overlapping {
+                let old = patches.remove(&s).unwrap();
+                let e = s + old.blocks();
+                if s < p_start {
+                    patches.insert(s, old.slice(0, p_start - s));
+                }
+                if p_end < e {
+                    patches.insert(p_end, old.slice(p_end - s, e - s));
+                }
+            }
+            patches.insert(p_start, patch);
+        }
+    }
 }
 
 #[derive(Clone, Derivative)]
@@ -258,21 +715,35 @@ pub struct ApmMap {
     update_partition_table: bool,
     partitions: Vec<PartitionEntry>,
     #[derivative(Debug = "ignore")]
-    raw_data: Vec<u8>,
+    storage: Storage,
+    /// Block ranges changed since decoding or the last flush
+    #[derivative(Debug = "ignore")]
+    dirty: Vec<(u32, u32)>,
 }
 
-fn apple_checksum(data: &[u8]) -> u16 {
+pub(crate) fn apple_checksum(data: &[u8]) -> u16 {
     let mut ret: u16 = 0;
     for b in data.iter() {
         ret = ret.wrapping_add(*b as u16);
         ret = ret.rotate_left(1);
     }
     if ret == 0 { ret = 0xffff }
-    assert_eq!(ret, 0x885f);
 
     ret
 }
 
+impl PartitionMap for ApmMap {
+    fn driver_desc(&self) -> &DriverDescriptorBlock {
+        &self.driver_desc
+    }
+    fn entries(&self) -> &[PartitionEntry] {
+        &self.partitions
+    }
+    fn device_blocks(&self) -> u32 {
+        self.storage.blocks()
+    }
+}
+
 impl ApmMap {
     pub fn new(blocks: u32) -> Self {
         Self {
@@ -287,55 +758,94 @@ impl ApmMap {
                     .with_name("Apple")
                     .with_type("Apple_partition_map"),
             ],
-            raw_data: vec![0; (blocks as usize)*512],
+            storage: Storage::Memory(vec![0; (blocks as usize)*512]),
+            dirty: vec![(0, blocks)],
         }
     }
-    pub fn block_size(&self) -> u16 { self.driver_desc.block_size }
-    pub fn blk_count(&self) -> u32 { self.driver_desc.blk_count }
-    pub fn dev_type(&self) -> u16 { self.driver_desc.dev_type }
-    pub fn dev_id(&self) -> u16 { self.driver_desc.dev_id }
-    pub fn data(&self) -> u32 { self.driver_desc.data }
-    pub fn driver_bytes(&self, num: usize) -> &[u8] {
-        let start = (self.driver_desc.drivers[num].start * 512) as usize;
-        let size = (self.driver_desc.drivers[num].size * 512) as usize;
-        &self.raw_data[start..][..size]
+    /// Reads `count` blocks starting at `start`, including changes that were not flushed yet
+    pub fn read_blocks(&self, start: u32, count: u32) -> Result<Cow<'_, [u8]>, ApmError> {
+        let end = self.block_range_end(start, count)?;
+        Ok(self.storage.read(start, end)?)
     }
-    pub fn push_partition<N, T>(&mut self, name: N, ty: T, data: &[u8]) -> Result<(), ApmError>
+    /// Blocks of a driver registered in the DDM
+    pub fn driver_bytes(&self, num: usize) -> Result<Cow<'_, [u8]>, ApmError> {
+        let (start, size) = self.driver_extent(num)?;
+        self.read_blocks(start, size)
+    }
+    pub fn push_partition<N, T>(&mut self, name: N, ty: T, data: &[u8], policy: AllocPolicy) -> Result<(), ApmError>
     where
         N: Into<String>, T: Into<String>,
     {
-        let size = (data.len() + 0x1ff & !0x1ff)/512;
-        let start = self.find_hole(size as u32)?;
+        let size = ((data.len() + 0x1ff) & !0x1ff)/512;
+        let start = self.find_hole(size as u32, policy)?;
         let entry = PartitionEntry::new()
             .with_start(start)
             .with_length(size as u32)
             .with_name(name)
             .with_type(ty);
-        self.partitions.push(entry);
-        self.raw_data[(start*512) as usize..][..data.len()].copy_from_slice(data);
-        self.update_partition_count();
-        self.update_partition_table = true;
+        self.insert_partition(entry, data)?;
         Ok(())
     }
-    pub fn push_partition_at<N, T, P>(&mut self, name: N, ty: T, proc: P, data: &[u8], start: u32) -> Result<(), ApmError>
-    where
-        N: Into<String>, T: Into<String>, P: Into<String>,
-    {
-        let size = (data.len() + 0x1ff & !0x1ff)/512;
-        let entry = PartitionEntry::new()
-            .with_start(start)
-            .with_length(size as u32)
-            .with_name(name)
-            .with_type(ty)
-            .with_checksum(0xf624)
-            .with_boot_code_size(9392)
-            .with_proc_type(proc);
-        println!("checksum {:04x}", apple_checksum(data));
+    /// Adds a partition described by `entry` to the map, copying `data` to its start and zeroing
+    /// the rest of it. Any `Apple_Free` entries covering that space are shrunk or split.
+    ///
+    /// Returns the index of the new partition.
+    pub fn insert_partition(&mut self, entry: PartitionEntry, data: &[u8]) -> Result<usize, ApmError> {
+        entry.validate()?;
+        let (start, length) = (entry.start, entry.length);
+        if length == 0 {
+            return Err(ApmError::EmptyPartition);
+        }
+        let end = start.checked_add(length)
+            .ok_or(ApmError::OutOfBounds(start, u32::MAX))?;
+        if start == 0 || end > self.device_blocks() {
+            return Err(ApmError::OutOfBounds(start, end));
+        }
+        if data.len() > length as usize * 512 {
+            return Err(ApmError::DataTooLarge(data.len(), length));
+        }
+        if self.partitions_used().any(|p| p.start < end && start < p.start + p.length) {
+            return Err(ApmError::Overlap(start, end));
+        }
+        let partitions = self.carve_free(start, end);
+        if partitions.len() >= self.map_capacity() {
+            return Err(ApmError::MapFull);
+        }
+        self.partitions = partitions;
+
+        self.store(start, data, end);
         self.partitions.push(entry);
-        self.raw_data[(start*512) as usize..][..data.len()].copy_from_slice(data);
         self.update_partition_count();
         self.update_partition_table = true;
-        Ok(())
+        Ok(self.partitions.len() - 1)
+    }
+    /// Returns the partition entries with `start..end` cut out of any `Apple_Free` entries
+    fn carve_free(&self, start: u32, end: u32) -> Vec<PartitionEntry> {
+        let mut ret = Vec::with_capacity(self.partitions.len() + 1);
+        for p in self.partitions.iter() {
+            let (p_start, p_end) = (p.start, p.start + p.length);
+            if p.part_type() != "Apple_Free" || p_end <= start || end <= p_start {
+                ret.push(p.clone());
+                continue;
+            }
+            for (s, e) in [(p_start, start), (end, p_end)] {
+                if s < e {
+                    ret.push(p.clone().with_start(s).with_length(e - s));
+                }
+            }
+        }
+        ret
+    }
+    /// Maximum number of entries the partition map can hold
+    fn map_capacity(&self) -> usize {
+        self.partitions.iter()
+            .find(|p| p.part_type() == "Apple_partition_map")
+            .map(|p| p.length as usize)
+            .unwrap_or(0x3f)
+    }
+    /// Number of blocks at the start of the device holding the DDM and the partition map
+    pub fn map_blocks(&self) -> u32 {
+        1 + self.map_capacity().max(self.partitions.len()) as u32
     }
     pub fn update_partition_count(&mut self) {
         let count = self.partitions.len();
@@ -343,62 +853,445 @@ impl ApmMap {
             p.partition_count = count as u32;
         }
     }
-    pub fn push_driver(&mut self, ty: u16, data: &[u8]) -> Result<(), ApmError> {
-        let size = (data.len() + 0x1ff & !0x1ff)/512;
-        let start = self.find_hole(size as u32)?;
-        self.driver_desc.push_driver_data(DriverData::new(start, size as u16, ty));
-        self.raw_data[(start*512) as usize..][..data.len()].copy_from_slice(data);
+    /// Adds a driver in a new partition and registers it in the DDM. The partition and processor
+    /// types are the usual ones for `ty`.
+    ///
+    /// Returns the index of the new driver.
+    pub fn push_driver(&mut self, ty: DriverType, data: &[u8], policy: AllocPolicy) -> Result<usize, ApmError> {
+        let entry = PartitionEntry::new()
+            .with_name("Macintosh")
+            .with_type(ty.partition_type())
+            .with_proc_type(ty.proc_type());
+        self.push_driver_partition(ty, entry, data, data.len(), policy)
+    }
+    /// Installs an Apple SCSI driver, such as the one written by Apple HD SC Setup, in an
+    /// `Apple_Driver43` partition. `data` may be a dump of a whole driver partition, of which the
+    /// first `code_len` bytes are the driver itself.
+    ///
+    /// Returns the index of the new driver.
+    pub fn install_driver43(&mut self, data: &[u8], code_len: usize, policy: AllocPolicy) -> Result<usize, ApmError> {
+        let entry = PartitionEntry::new()
+            .with_name("Macintosh")
+            .with_type("Apple_Driver43")
+            .with_proc_type("68000");
+        self.push_driver_partition(DriverType::MacOs68k, entry, data, code_len, policy)
+    }
+    /// Copies driver `num` of `source` together with the rest of its partition, keeping the name,
+    /// partition type and processor type. The driver length comes from the boot code size of the
+    /// partition, or from the DDM if the partition does not record it.
+    ///
+    /// Returns the index of the new driver.
+    pub fn copy_driver(&mut self, source: &ApmMap, num: usize, policy: AllocPolicy) -> Result<usize, ApmError> {
+        let driver = source.driver_desc.drivers.get(num)
+            .ok_or(ApmError::NoDriver(num))?;
+        let idx = source.driver_partition(num)
+            .ok_or(ApmError::NoPartition(num))?;
+        let old = &source.partitions[idx];
+        let entry = PartitionEntry::new()
+            .with_name(old.name())
+            .with_type(old.part_type())
+            .with_proc_type(old.proc_type());
+        let data = source.partition_data(idx)?;
+        let code_len = match old.boot_size() {
+            0 => (driver.size as usize*512).min(data.len()),
+            len => len as usize,
+        };
+        self.push_driver_partition(driver.driver_type(), entry, &data, code_len, policy)
+    }
+    /// Puts `data` in a new driver partition named and typed like `entry`, with the first
+    /// `code_len` bytes being the driver as registered in the DDM and covered by the checksum
+    ///
+    /// Returns the index of the new driver.
+    pub fn push_driver_partition(&mut self, ty: DriverType, entry: PartitionEntry, data: &[u8], code_len: usize, policy: AllocPolicy) -> Result<usize, ApmError> {
+        if code_len == 0 || code_len > data.len() {
+            return Err(ApmError::DataTooSmall(data.len(), code_len.div_ceil(512) as u32));
+        }
+        let code = &data[..code_len];
+        let code_size = u16::try_from(code_len.div_ceil(512))
+            .map_err(|_| ApmError::DataTooLarge(code_len, u16::MAX as u32))?;
+        let size = data.len().div_ceil(512) as u32;
+        let start = self.find_hole(size, policy)?;
+        let entry = entry
+            .with_start(start)
+            .with_length(size)
+            .with_status(status::DRIVER)
+            .with_boot_code_size(code_len as u32)
+            .with_checksum(apple_checksum(code) as u32);
+        self.insert_partition(entry, data)?;
+        self.driver_desc.push_driver_data(DriverData::new(start, code_size, ty));
+        self.update_driver_desc = true;
+        Ok(self.driver_desc.drivers.len() - 1)
+    }
+    /// Replaces the code of a driver, growing its partition into free space if needed
+    pub fn replace_driver(&mut self, num: usize, data: &[u8]) -> Result<(), ApmError> {
+        let size = ((data.len() + 0x1ff) & !0x1ff)/512;
+        let size_u16 = u16::try_from(size)
+            .map_err(|_| ApmError::DataTooLarge(data.len(), u16::MAX as u32))?;
+        let driver = self.driver_desc.drivers.get(num)
+            .ok_or(ApmError::NoDriver(num))?
+            .clone();
+        match self.driver_partition(num) {
+            Some(mut idx) => {
+                if size as u32 > self.partitions[idx].length {
+                    idx = self.resize_partition(idx, size as u32)?;
+                }
+                self.write_partition_data(idx, data, Fit::Pad)?;
+                let entry = &mut self.partitions[idx];
+                entry.boot_size = data.len() as u32;
+                entry.boot_checksum = apple_checksum(data) as u32;
+                self.update_partition_table = true;
+            },
+            None => {
+                if size_u16 > driver.size {
+                    return Err(ApmError::DataTooLarge(data.len(), driver.size as u32));
+                }
+                let end = driver.start + driver.size as u32;
+                if end > self.device_blocks() {
+                    return Err(ApmError::OutOfBounds(driver.start, end));
+                }
+                self.store(driver.start, data, end);
+            },
+        }
+        self.driver_desc.drivers[num].size = size_u16;
         self.update_driver_desc = true;
         Ok(())
     }
-    fn find_hole(&self, size: u32) -> Result<u32, ApmError> {
-        let mut hole = 0x1;
-        for (p, _) in self.partitions_used() {
-            hole = p.start + p.length;
-        }
-        if hole + size > self.raw_data.len() as u32/512 {
-            Err(ApmError::NoSpace)
-        } else {
-            Ok(hole)
+    /// Removes a driver from the DDM and turns its partition into free space
+    pub fn remove_driver(&mut self, num: usize) -> Result<(), ApmError> {
+        let idx = self.driver_partition(num);
+        self.driver_desc.remove_driver_data(num)
+            .ok_or(ApmError::NoDriver(num))?;
+        if let Some(idx) = idx {
+            let entry = &self.partitions[idx];
+            self.partitions[idx] = PartitionEntry::new()
+                .with_start(entry.start)
+                .with_length(entry.length)
+                .with_name("Extra")
+                .with_type("Apple_Free")
+                .with_status(0);
+            self.update_partition_table = true;
         }
+        self.update_driver_desc = true;
+        Ok(())
     }
-    pub fn drivers(&self) -> impl Iterator<Item = (&DriverData, &[u8])> {
-        self.driver_desc.drivers.iter()
-            .map(|driver| {
-                (driver, &self.raw_data[(driver.start * 512) as usize..][..(driver.size * 512) as usize])
-            })
+    /// Lists unused areas of the device as `(start, end)` block ranges. Space covered by
+    /// `Apple_Free` entries counts as unused, driver data referenced by the DDM does not.
+    pub fn holes(&self) -> Vec<(u32, u32)> {
+        let used = self.partitions_used()
+            .map(|p| (p.start, p.start + p.length))
+            .chain(self.driver_desc.drivers.iter().map(|d| (d.start, d.start + d.size as u32)))
+            .chain(std::iter::once((0, 1)))
+            .collect();
+        holes_between(used, self.device_blocks())
     }
-    pub fn partition_data(&self, idx: usize) -> Option<&[u8]> {
-        self.partitions.iter()
-            .enumerate()
-            .find(|(i, _)| *i == idx)
-            .map(|(_, p)| (p.start, p.length))
-            .map(|(start, length)| &self.raw_data[(start*512) as usize..][..(length*512) as usize])
+    /// Finds the first block of an unused area at least `size` blocks long, as chosen by `policy`
+    pub fn find_hole(&self, size: u32, policy: AllocPolicy) -> Result<u32, ApmError> {
+        policy.place(&self.holes(), size)
+    }
+    pub fn partition_data(&self, idx: usize) -> Result<Cow<'_, [u8]>, ApmError> {
+        let (start, length) = self.partition_extent(idx)?;
+        self.read_blocks(start, length)
+    }
+    /// Reads the contents of a partition piece by piece
+    pub fn partition_reader(&self, idx: usize) -> Result<PartitionReader<'_>, ApmError> {
+        let (start, length) = self.partition_extent(idx)?;
+        Ok(PartitionReader::new(self, start, length))
     }
+    /// Writes the contents of a partition piece by piece. Like all other changes, they reach the
+    /// image with the next `flush`.
+    pub fn partition_writer(&mut self, idx: usize) -> Result<PartitionWriter<'_>, ApmError> {
+        let (start, length) = self.partition_extent(idx)?;
+        Ok(PartitionWriter::new(self, start, length))
+    }
+    /// Mutable access to the contents of a partition, which is written out by the next `flush`.
+    /// Maps opened on a device have to use `write_partition_data` instead.
     pub fn partition_data_mut(&mut self, idx: usize) -> Option<&mut [u8]> {
-        self.partitions.iter()
+        let (start, length) = self.partitions.get(idx)
+            .map(|p| (p.start, p.length))?;
+        let Storage::Memory(raw) = &mut self.storage else {
+            return None;
+        };
+        if length > 0 {
+            self.dirty.push((start, start + length));
+        }
+        Some(&mut raw[(start*512) as usize..][..(length*512) as usize])
+    }
+    /// Replaces contents of a partition with `data`, resolving size differences according to `fit`
+    pub fn write_partition_data(&mut self, idx: usize, data: &[u8], fit: Fit) -> Result<(), ApmError> {
+        let (start, length) = self.partitions.get(idx)
+            .map(|p| (p.start, p.length))
+            .ok_or(ApmError::NoPartition(idx))?;
+        let size = length as usize*512;
+        let data = match (data.len().cmp(&size), fit) {
+            (Ordering::Equal, _) | (Ordering::Less, Fit::Pad) => data,
+            (Ordering::Greater, Fit::Truncate) => &data[..size],
+            (Ordering::Less, _) => return Err(ApmError::DataTooSmall(data.len(), length)),
+            (Ordering::Greater, _) => return Err(ApmError::DataTooLarge(data.len(), length)),
+        };
+        self.store(start, data, start + length);
+        Ok(())
+    }
+    /// Changes the length of a partition, growing only into unused space directly after it.
+    /// Newly added blocks are zeroed.
+    ///
+    /// Returns the new index of the partition, which changes when an `Apple_Free` entry before it
+    /// is consumed.
+    pub fn resize_partition(&mut self, idx: usize, length: u32) -> Result<usize, ApmError> {
+        let entry = self.partitions.get(idx)
+            .ok_or(ApmError::NoPartition(idx))?;
+        let (start, old_end) = (entry.start, entry.start + entry.length);
+        let end = start.checked_add(length)
+            .ok_or(ApmError::OutOfBounds(start, u32::MAX))?;
+        if end > old_end {
+            if end > self.device_blocks() {
+                return Err(ApmError::OutOfBounds(old_end, end));
+            }
+            if self.partitions_used().any(|p| p.start < end && old_end < p.start + p.length) {
+                return Err(ApmError::Overlap(old_end, end));
+            }
+            let partitions = self.carve_free(old_end, end);
+            if partitions.len() > self.map_capacity() {
+                return Err(ApmError::MapFull);
+            }
+            self.partitions = partitions;
+            self.store(old_end, &[], end);
+        }
+        let idx = self.partitions.iter()
+            .position(|p| p.start == start && p.part_type() != "Apple_Free")
+            .ok_or(ApmError::NoPartition(idx))?;
+        self.partitions[idx].set_length(length);
+        self.update_partition_table = true;
+        Ok(idx)
+    }
+    /// Grows the HFS, HFS+ or HFSX volume in a partition to fill it, after `resize_partition`
+    /// grew the partition from `old_length` blocks.
+    ///
+    /// Returns how many bytes at the end of the partition the volume cannot use, see `hfs::grow`.
+    pub fn grow_volume(&mut self, idx: usize, old_length: u32) -> Result<u64, ApmError> {
+        let mut writer = self.partition_writer(idx)?;
+        Ok(hfs::grow(&mut writer, old_length as u64*512)?)
+    }
+    /// Changes the size of the device to `blocks`, updating the DDM. Space added at the end goes
+    /// to the last partition if `grow_last` is set and to a trailing `Apple_Free` entry otherwise.
+    /// Shrinking cuts off only unused space.
+    ///
+    /// Blocks past the old end are not written unless the last partition grows into them, so the
+    /// image has to be resized before the next `flush`, see `Device::set_len`.
+    ///
+    /// Returns the new index and the old length of the last partition if it grew, as needed by
+    /// `grow_volume`.
+    pub fn resize_device(&mut self, blocks: u32, grow_last: bool) -> Result<Option<(usize, u32)>, ApmError> {
+        let used_end = self.partitions_used()
+            .map(|p| p.start + p.length)
+            .chain(self.driver_desc.drivers.iter().map(|d| d.start + d.size as u32))
+            .chain(std::iter::once(self.map_blocks()))
+            .max()
+            .unwrap_or(1);
+        if used_end > blocks {
+            return Err(ApmError::OutOfBounds(blocks, used_end));
+        }
+        let last = self.partitions.iter()
             .enumerate()
-            .find(|(i, _)| *i == idx)
-            .map(|(_, p)| (p.start, p.length))
-            .map(|(start, length)| &mut self.raw_data[(start*512) as usize..][..(length*512) as usize])
+            .filter(|(_, p)| p.part_type() != "Apple_Free")
+            .max_by_key(|(_, p)| p.start + p.length)
+            .filter(|(_, p)| p.part_type() != "Apple_partition_map")
+            .map(|(i, p)| (i, p.start, p.length));
+        let last = match (grow_last, last) {
+            (true, None) => return Err(ApmError::NoPartition(1)),
+            (true, last) => last,
+            (false, _) => None,
+        };
+
+        self.storage.resize(blocks);
+        for range in self.dirty.iter_mut() {
+            range.1 = range.1.min(blocks);
+        }
+        self.dirty.retain(|(start, end)| start < end);
+        self.partitions.retain(|p| p.part_type() != "Apple_Free" || p.start < blocks);
+        for p in self.partitions.iter_mut().filter(|p| p.part_type() == "Apple_Free") {
+            p.set_length(p.length.min(blocks - p.start));
+        }
+        self.update_partition_table = true;
+
+        let mut grown = None;
+        if let Some((idx, start, length)) = last {
+            grown = Some((self.resize_partition(idx, blocks - start)?, length));
+        } else if let Some(end) = self.partitions.iter().map(|p| p.start + p.length).max().filter(|end| *end < blocks) {
+            let room = self.partitions.len() < self.map_capacity();
+            match self.partitions.iter_mut().find(|p| p.part_type() == "Apple_Free" && p.start + p.length == end) {
+                Some(free) => free.set_length(blocks - free.start),
+                None if room => {
+                    self.partitions.push(PartitionEntry::new()
+                        .with_start(end)
+                        .with_length(blocks - end)
+                        .with_name("Extra")
+                        .with_type("Apple_Free")
+                        .with_status(0));
+                    self.update_partition_count();
+                },
+                None => (),
+            }
+        }
+        self.driver_desc.set_blk_count(blocks);
+        self.update_driver_desc = true;
+        Ok(grown)
     }
-    pub fn partitions_used(&self) -> impl Iterator<Item = (&PartitionEntry, &[u8])> {
-        self.partitions()
-            .filter(|(p, _)| p.part_type() != "Apple_Free")
+    /// Moves partitions towards the start of the device, closing the gaps between them. The map
+    /// and drivers registered in the DDM stay where they are, unused space is described by
+    /// `Apple_Free` entries, a single one at the end unless drivers are in the way.
+    ///
+    /// Everything is written to `target`, which has to be the image or device the map was read
+    /// from or a copy of it: changes made before are flushed first, then partitions are copied
+    /// piece by piece, and the new map goes last.
+    ///
+    /// Map entries are sorted by their first block afterwards. Returns the old and new first
+    /// block of every moved partition.
+    pub fn compact<W: io::Write + io::Seek>(&mut self, target: &mut W) -> Result<Vec<(u32, u32)>, ApmError> {
+        let drivers: Vec<(u32, u32)> = self.driver_desc.drivers.iter()
+            .map(|d| (d.start, d.start + d.size as u32))
+            .collect();
+        let fixed = |p: &PartitionEntry| p.part_type() == "Apple_partition_map"
+            || drivers.iter().any(|(start, _)| *start == p.start);
+        let pinned: Vec<(u32, u32)> = self.partitions_used()
+            .filter(|p| fixed(p))
+            .map(|p| (p.start, p.start + p.length))
+            .chain(drivers.iter().copied())
+            .chain(std::iter::once((0, 1)))
+            .collect();
+        let mut order: Vec<usize> = (0..self.partitions.len())
+            .filter(|i| self.partitions[*i].part_type() != "Apple_Free" && !fixed(&self.partitions[*i]))
+            .collect();
+        order.sort_by_key(|i| self.partitions[*i].start);
+        for idx in &order {
+            self.partition_extent(*idx)?;
+        }
+        self.flush(target)?;
+
+        let mut moves = Vec::new();
+        let mut pos = 0;
+        for idx in order {
+            let (start, length) = (self.partitions[idx].start, self.partitions[idx].length);
+            let mut to = pos;
+            while let Some((_, end)) = pinned.iter().find(|(s, e)| *s < to + length.max(1) && to < *e) {
+                to = *end;
+            }
+            if to >= start {
+                pos = start + length;
+                continue;
+            }
+            // Partitions are moved in the order of their first blocks, so nothing is overwritten
+            // before it was moved itself
+            self.move_blocks(start, to, length, target)?;
+            self.partitions[idx].set_start(to);
+            moves.push((start, to));
+            pos = to + length;
+        }
+
+        self.list_free_space();
+        self.flush(target)?;
+        Ok(moves)
     }
-    pub fn partitions(&self) -> impl Iterator<Item = (&PartitionEntry, &[u8])> {
-        self.partitions.iter()
-            .map(|p| {
-                (p, &self.raw_data[(p.start*512) as usize..][..(p.length * 512) as usize] )
-            })
+    /// Replaces all `Apple_Free` entries with new ones for the holes between partitions, as
+    /// many as the map has room for, and sorts the entries by their first block
+    pub(crate) fn list_free_space(&mut self) {
+        self.partitions.retain(|p| p.part_type() != "Apple_Free");
+        self.partitions.sort_by_key(|p| p.start);
+        for (start, end) in self.holes() {
+            if self.partitions.len() >= self.map_capacity() {
+                break;
+            }
+            self.partitions.push(PartitionEntry::new()
+                .with_start(start)
+                .with_length(end - start)
+                .with_name("Extra")
+                .with_type("Apple_Free")
+                .with_status(0));
+        }
+        self.partitions.sort_by_key(|p| p.start);
+        self.update_partition_table = true;
+    }
+    /// Copies `count` blocks from `from` to `to`, which must not lie after `from` when the
+    /// ranges overlap. Images kept in memory are changed there and marked dirty, devices are
+    /// written to `target` right away, which must be the same device or a copy of it.
+    fn move_blocks<W: io::Write + io::Seek>(&mut self, from: u32, to: u32, count: u32, target: &mut W) -> Result<(), ApmError> {
+        match &mut self.storage {
+            Storage::Memory(raw) => {
+                raw.copy_within(from as usize*512..(from + count) as usize*512, to as usize*512);
+                self.mark_dirty(to, to + count);
+            },
+            Storage::Device(..) => {
+                target.seek(io::SeekFrom::Start(to as u64*512))?;
+                for chunk in (0..count).step_by(FLUSH_CHUNK as usize) {
+                    let n = FLUSH_CHUNK.min(count - chunk);
+                    target.write_all(&self.storage.read(from + chunk, from + chunk + n)?)?;
+                }
+                target.flush()?;
+            },
+        }
+        Ok(())
+    }
+    /// Mutable access to a map entry, the map is rewritten by the next `encode`
+    pub fn partition_entry_mut(&mut self, idx: usize) -> Option<&mut PartitionEntry> {
+        self.update_partition_table = true;
+        self.partitions.get_mut(idx)
+    }
+    /// Encodes a single map entry, returning its byte offset in the device and its contents
+    pub fn encode_partition_entry(&self, idx: usize) -> Result<Option<(u64, Vec<u8>)>, ApmError> {
+        let Some(entry) = self.partitions.get(idx) else {
+            return Ok(None);
+        };
+        entry.validate()?;
+        Ok(Some((512 + idx as u64*512, entry.to_bytes()?)))
     }
-    pub fn raw(&self) -> &[u8] {
-        &self.raw_data
+    /// The whole image, unless the map was opened on a device
+    pub fn raw(&self) -> Option<&[u8]> {
+        match &self.storage {
+            Storage::Memory(raw) => Some(raw),
+            Storage::Device(..) => None,
+        }
     }
     pub fn decode(data: Vec<u8>) -> Result<Self, ApmError> {
+        let (driver_desc, partitions) = Self::parse(&data)?;
+        Ok(Self {
+            update_driver_desc: false,
+            driver_desc,
+            update_partition_table: false,
+            partitions,
+            storage: Storage::Memory(data),
+            dirty: Vec::new(),
+        })
+    }
+    /// Reads the DDM and partition map of an image or device, leaving everything else on it
+    /// until it is needed
+    pub fn open(device: Device) -> Result<Self, ApmError> {
+        let blocks = (device.size()/512).min(u32::MAX as u64) as u32;
+        if blocks < 2 {
+            return Err(ApmError::OutOfBounds(0, 2));
+        }
+        let mut head = vec![0; 1024];
+        device.read_at(0, &mut head)?;
+        let (_, first) = PartitionEntry::from_bytes((&head[512..], 0))?;
+        let count = first.partition_count.clamp(1, blocks - 1);
+        head.resize((1 + count as usize)*512, 0);
+        device.read_at(1024, &mut head[1024..])?;
+
+        let (driver_desc, partitions) = Self::parse(&head)?;
+        Ok(Self {
+            update_driver_desc: false,
+            driver_desc,
+            update_partition_table: false,
+            partitions,
+            storage: Storage::Device(Arc::new(device), BTreeMap::new(), blocks),
+            dirty: Vec::new(),
+        })
+    }
+    /// Parses the DDM and the map entries following it
+    pub(crate) fn parse(data: &[u8]) -> Result<(DriverDescriptorBlock, Vec<PartitionEntry>), ApmError> {
         let mut iter = data.chunks(512).enumerate();
         let mut partitions = Vec::new();
-        let driver_bytes = iter.next().unwrap().1;
+        let driver_bytes = iter.next()
+            .ok_or(ApmError::OutOfBounds(0, 1))?
+            .1;
         let driver_desc = DriverDescriptorBlock::from_bytes((driver_bytes, 0))?.1;
         for (i, block) in iter {
             let (_, entry) = PartitionEntry::from_bytes((block, 0))?;
@@ -408,29 +1301,431 @@ impl ApmMap {
                 break;
             }
         }
-
-        Ok(Self {
-            update_driver_desc: false,
-            driver_desc,
-            update_partition_table: false,
-            partitions,
-            raw_data: data,
-        })
+        Ok((driver_desc, partitions))
+    }
+    fn mark_dirty(&mut self, start: u32, end: u32) {
+        match self.dirty.last_mut() {
+            // Streamed writes come in many small pieces
+            Some(last) if last.1 == start => last.1 = last.1.max(end),
+            _ if start < end => self.dirty.push((start, end)),
+            _ => (),
+        }
+    }
+    /// Puts `data` at the start of block `start` and zeroes the rest up to block `end`
+    fn store(&mut self, start: u32, data: &[u8], end: u32) {
+        self.storage.write(start, data, end);
+        self.mark_dirty(start, end);
+    }
+    /// Writes `data` at byte `offset`, which must lie within the device
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let (start, end) = ((offset/512) as u32, (offset + data.len() as u64).div_ceil(512) as u32);
+        match &mut self.storage {
+            Storage::Memory(raw) => raw[offset as usize..][..data.len()].copy_from_slice(data),
+            Storage::Device(..) => {
+                let mut blocks = self.storage.read(start, end)?.into_owned();
+                blocks[(offset - start as u64*512) as usize..][..data.len()].copy_from_slice(data);
+                self.storage.write(start, &blocks, end);
+            },
+        }
+        self.mark_dirty(start, end);
+        Ok(())
     }
-    pub fn encode(&mut self) -> Result<&[u8], ApmError> {
+    /// Writes `bytes` at the start of `block`, marking the block dirty if its contents change
+    fn write_block(&mut self, block: u32, bytes: &[u8]) -> Result<(), ApmError> {
+        let old = self.read_blocks(block, 1)?;
+        if old[..bytes.len()] != *bytes {
+            let mut new = old.into_owned();
+            new[..bytes.len()].copy_from_slice(bytes);
+            self.store(block, &new, block + 1);
+        }
+        Ok(())
+    }
+    /// Brings the DDM and partition map blocks up to date with the changes made to them
+    pub fn encode(&mut self) -> Result<(), ApmError> {
         if self.update_driver_desc {
             let block0 = self.driver_desc.to_bytes()?;
-            self.raw_data[..512][..block0.len()].copy_from_slice(&block0);
+            self.write_block(0, &block0)?;
+            self.update_driver_desc = false;
         }
 
         if self.update_partition_table {
+            for entry in &self.partitions {
+                entry.validate()?;
+            }
             self.update_partition_count();
-            for (i, entry) in self.partitions.iter().enumerate() {
-                let bytes = entry.to_bytes()?;
-                self.raw_data[512+i*512..][..bytes.len()].copy_from_slice(&bytes);
+            let entries = self.partitions.iter()
+                .map(|entry| entry.to_bytes())
+                .collect::<Result<Vec<_>, _>>()?;
+            for (i, bytes) in entries.iter().enumerate() {
+                self.write_block(1 + i as u32, bytes)?;
+            }
+            // Entries dropped from the map must not be picked up again
+            let capacity = self.map_capacity().max(self.partitions.len());
+            for block in 1 + self.partitions.len()..1 + capacity {
+                self.write_block(block as u32, &[0; 512])?;
+            }
+            self.update_partition_table = false;
+        }
+
+        Ok(())
+    }
+    /// Block ranges changed since decoding or the last `flush`, sorted and merged. Map and DDM
+    /// changes only show up here after `encode`.
+    pub fn dirty_ranges(&self) -> Vec<(u32, u32)> {
+        let mut ranges = self.dirty.clone();
+        ranges.sort_unstable();
+        let mut ret: Vec<(u32, u32)> = Vec::new();
+        for (start, end) in ranges {
+            match ret.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => ret.push((start, end)),
             }
         }
+        ret
+    }
+    /// Encodes the map and writes only the changed blocks to `target`, which should hold the
+    /// image this map was read from. Maps opened on a device drop their changes from memory
+    /// afterwards, so for them `target` should be that device.
+    pub fn flush<W: io::Write + io::Seek>(&mut self, target: &mut W) -> Result<(), ApmError> {
+        self.encode()?;
+        // The map goes last, so that it never describes data that was not written yet
+        let map_end = self.map_blocks();
+        let mut data = Vec::new();
+        let mut map = Vec::new();
+        for (start, end) in self.dirty_ranges() {
+            if start < map_end {
+                map.push((start, end.min(map_end)));
+            }
+            if end > map_end {
+                data.push((start.max(map_end), end));
+            }
+        }
+        for (start, end) in data.into_iter().chain(map) {
+            target.seek(io::SeekFrom::Start(start as u64*512))?;
+            for chunk in (start..end).step_by(FLUSH_CHUNK as usize) {
+                target.write_all(&self.storage.read(chunk, end.min(chunk + FLUSH_CHUNK))?)?;
+            }
+        }
+        target.flush()?;
+        if let Storage::Device(_, patches, _) = &mut self.storage {
+            patches.clear();
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Seek, Write};
+
+    /// An image in memory remembering which blocks were written to it, in order
+    struct Recorder {
+        image: Cursor<Vec<u8>>,
+        writes: Vec<(u32, u32)>,
+    }
+
+    impl Recorder {
+        fn new(image: Vec<u8>) -> Self {
+            Self { image: Cursor::new(image), writes: Vec::new() }
+        }
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let start = (self.image.position()/512) as u32;
+            let end = (self.image.position() + buf.len() as u64).div_ceil(512) as u32;
+            match self.writes.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => self.writes.push((start, end)),
+            }
+            self.image.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Recorder {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.image.seek(pos)
+        }
+    }
+
+    fn pattern(blocks: u32, seed: u8) -> Vec<u8> {
+        (0..blocks as usize*512).map(|i| (i/512) as u8 ^ seed).collect()
+    }
+
+    /// A map of `blocks` blocks with partitions of the given first blocks and lengths, each
+    /// filled with a pattern, decoded from an image so that nothing is dirty
+    fn image(blocks: u32, partitions: &[(u32, u32)]) -> ApmMap {
+        let mut map = ApmMap::new(blocks);
+        for (i, &(start, length)) in partitions.iter().enumerate() {
+            let entry = PartitionEntry::new()
+                .with_start(start)
+                .with_length(length)
+                .with_name(format!("P{}", i))
+                .with_type("Apple_HFS");
+            map.insert_partition(entry, &pattern(length, i as u8)).unwrap();
+        }
+        let mut out = Cursor::new(vec![0; blocks as usize*512]);
+        map.flush(&mut out).unwrap();
+        ApmMap::decode(out.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn flush_writes_only_dirty_ranges() {
+        let mut map = image(4096, &[(100, 50), (200, 50)]);
+        let mut out = Recorder::new(map.raw().unwrap().to_vec());
+        map.flush(&mut out).unwrap();
+        assert!(out.writes.is_empty());
+
+        map.write_partition_data(2, &pattern(50, 9), Fit::Exact).unwrap();
+        map.partition_entry_mut(1).unwrap().set_name("Renamed");
+        map.flush(&mut out).unwrap();
+        // Data goes before the map, and unchanged map entries are left alone
+        assert_eq!(out.writes, [(200, 250), (2, 3)]);
+        assert!(map.dirty_ranges().is_empty());
+
+        let written = ApmMap::decode(out.image.into_inner()).unwrap();
+        assert_eq!(written.partition_entry(1).unwrap().name(), "Renamed");
+        assert_eq!(written.partition_data(2).unwrap(), pattern(50, 9));
+        assert_eq!(written.partition_data(1).unwrap(), pattern(50, 0));
+    }
+
+    #[test]
+    fn validate_checks_macroman_lengths() {
+        // 32 bytes in MacRoman, though more in UTF-8
+        let name = format!("{}é", "a".repeat(31));
+        let entry = PartitionEntry::new().with_name(name.clone()).with_type("Apple_HFS");
+        entry.validate().unwrap();
+        let (_, decoded) = PartitionEntry::from_bytes((&entry.to_bytes().unwrap(), 0)).unwrap();
+        assert_eq!(decoded.name(), name);
+
+        let long = "é".repeat(33);
+        let entry = PartitionEntry::new().with_name(long.clone());
+        assert!(matches!(entry.validate(), Err(ApmError::StringTooLong(s, 32)) if s == long));
+        let entry = PartitionEntry::new().with_name("Ω ≈ 日本");
+        assert!(matches!(entry.validate(), Err(ApmError::NotMacRoman(_))));
+        let entry = PartitionEntry::new().with_type(format!("Apple_{}", "x".repeat(27)));
+        assert!(matches!(entry.validate(), Err(ApmError::StringTooLong(_, 32))));
+        let entry = PartitionEntry::new().with_proc_type("x".repeat(17));
+        assert!(matches!(entry.validate(), Err(ApmError::StringTooLong(_, 16))));
+    }
+
+    #[test]
+    fn alloc_policy_places_by_strategy() {
+        let holes = [(10, 20), (30, 100), (200, 250), (300, 1000)];
+        let place = |strategy, align, size| AllocPolicy::new()
+            .with_strategy(strategy)
+            .with_align(align)
+            .place(&holes, size);
+        assert_eq!(place(Strategy::FirstFit, 1, 10).unwrap(), 10);
+        assert_eq!(place(Strategy::FirstFit, 1, 11).unwrap(), 30);
+        assert_eq!(place(Strategy::BestFit, 1, 10).unwrap(), 10);
+        assert_eq!(place(Strategy::BestFit, 1, 40).unwrap(), 200);
+        assert_eq!(place(Strategy::BestFit, 1, 100).unwrap(), 300);
+        assert_eq!(place(Strategy::EndOfDisk, 1, 10).unwrap(), 990);
+        assert_eq!(place(Strategy::EndOfDisk, 1, 700).unwrap(), 300);
+        assert!(matches!(place(Strategy::FirstFit, 1, 701), Err(ApmError::NoSpace)));
+    }
+
+    #[test]
+    fn alloc_policy_rounds_to_alignment() {
+        let holes = [(10, 20), (30, 100), (200, 250), (300, 1000)];
+        let place = |strategy, align, size| AllocPolicy::new()
+            .with_strategy(strategy)
+            .with_align(align)
+            .place(&holes, size);
+        // Rounding up to block 16 leaves too little of the first hole
+        assert_eq!(place(Strategy::FirstFit, 8, 10).unwrap(), 32);
+        assert_eq!(place(Strategy::BestFit, 8, 10).unwrap(), 200);
+        assert_eq!(place(Strategy::EndOfDisk, 8, 10).unwrap(), 984);
+        assert_eq!(place(Strategy::FirstFit, 0, 10).unwrap(), 10);
+        // The only hole is long enough, but not once its start is aligned
+        let holes = [(1, 9)];
+        assert_eq!(AllocPolicy::new().place(&holes, 8).unwrap(), 1);
+        for strategy in [Strategy::FirstFit, Strategy::BestFit, Strategy::EndOfDisk] {
+            let policy = AllocPolicy::new().with_strategy(strategy).with_align(2);
+            assert!(matches!(policy.place(&holes, 8), Err(ApmError::NoSpace)));
+        }
+    }
+
+    #[test]
+    fn insert_rejects_empty_partitions() {
+        let mut map = image(4096, &[(100, 50)]);
+        let entry = PartitionEntry::new().with_start(300).with_length(0).with_type("Apple_HFS");
+        assert!(matches!(map.insert_partition(entry, &[]), Err(ApmError::EmptyPartition)));
+        assert!(matches!(map.push_partition("Empty", "Apple_HFS", &[], AllocPolicy::new()), Err(ApmError::EmptyPartition)));
+        assert_eq!(map.partitions().count(), 2);
+    }
+
+    #[test]
+    fn compact_moves_around_pinned_drivers() {
+        // A driver at 80 stays, the first partition moves onto most of itself
+        let mut map = image(4096, &[(80, 10), (300, 400), (1000, 20)]);
+        map.driver_desc.push_driver_data(DriverData::new(80, 10, DriverType::MacOs68k));
+        map.update_driver_desc = true;
+        let mut out = Recorder::new(map.raw().unwrap().to_vec());
+        let moves = map.compact(&mut out).unwrap();
+        assert_eq!(moves, [(300, 90), (1000, 490)]);
+        assert!(out.writes.last().unwrap().1 <= map.map_blocks());
+
+        let written = ApmMap::decode(out.image.into_inner()).unwrap();
+        let layout: Vec<(u32, u32, &str)> = written.partitions()
+            .map(|p| (p.start(), p.length(), p.part_type()))
+            .collect();
+        assert_eq!(layout, [
+            (1, 63, "Apple_partition_map"),
+            (64, 16, "Apple_Free"),
+            (80, 10, "Apple_HFS"),
+            (90, 400, "Apple_HFS"),
+            (490, 20, "Apple_HFS"),
+            (510, 3586, "Apple_Free"),
+        ]);
+        assert_eq!(written.partition_data(2).unwrap(), pattern(10, 0));
+        assert_eq!(written.partition_data(3).unwrap(), pattern(400, 1));
+        assert_eq!(written.partition_data(4).unwrap(), pattern(20, 2));
+
+        let mut map = written;
+        let mut out = Recorder::new(map.raw().unwrap().to_vec());
+        assert!(map.compact(&mut out).unwrap().is_empty());
+    }
+
+    #[test]
+    fn install_and_copy_driver43() {
+        // A dump of a whole driver partition, padded after 3000 bytes of code
+        let mut dump = pattern(8, 5);
+        dump[3000..].fill(0);
+        let mut source = ApmMap::new(4096);
+        let num = source.install_driver43(&dump, 3000, AllocPolicy::new()).unwrap();
+        let idx = source.driver_partition(num).unwrap();
+        let entry = source.partition_entry(idx).unwrap().clone();
+        assert_eq!((entry.part_type(), entry.boot_size(), entry.length()), ("Apple_Driver43", 3000, 8));
+        assert_eq!(entry.boot_checksum(), apple_checksum(&dump[..3000]) as u32);
+        assert_eq!(source.drivers().next().unwrap().size(), 6);
+        assert!(matches!(source.install_driver43(&dump, 0, AllocPolicy::new()), Err(ApmError::DataTooSmall(..))));
+        assert!(matches!(source.install_driver43(&dump, 5000, AllocPolicy::new()), Err(ApmError::DataTooSmall(..))));
+
+        let mut copy = ApmMap::new(4096);
+        copy.copy_driver(&source, num, AllocPolicy::new()).unwrap();
+        assert_eq!(copy.partition_entry(1).unwrap().boot_checksum(), entry.boot_checksum());
+
+        // Without a boot code size, the driver is as long as the DDM says
+        source.partition_entry_mut(idx).unwrap().boot_size = 0;
+        let mut copy = ApmMap::new(4096);
+        copy.copy_driver(&source, num, AllocPolicy::new()).unwrap();
+        assert_eq!(copy.partition_entry(1).unwrap().boot_size(), 6*512);
+        assert_eq!(copy.drivers().next().unwrap().size(), 6);
+    }
+
+    #[test]
+    fn resize_device_and_partitions() {
+        let mut map = image(4096, &[(100, 50), (1000, 20)]);
+        let free = |map: &ApmMap| map.partitions()
+            .filter(|p| p.part_type() == "Apple_Free")
+            .map(|p| (p.start(), p.length()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(map.resize_device(8192, false).unwrap(), None);
+        assert_eq!((map.blk_count(), map.device_blocks()), (8192, 8192));
+        assert_eq!(free(&map), [(1020, 7172)]);
+        assert_eq!(map.resize_device(2048, false).unwrap(), None);
+        assert_eq!(free(&map), [(1020, 1028)]);
+        // Only unused space can be cut off
+        assert!(matches!(map.resize_device(1000, false), Err(ApmError::OutOfBounds(1000, 1020))));
+        assert_eq!(map.blk_count(), 2048);
+
+        let (idx, old) = map.resize_device(4096, true).unwrap().unwrap();
+        assert_eq!(old, 20);
+        let last = map.partition_entry(idx).unwrap();
+        assert_eq!((last.start(), last.length()), (1000, 3096));
+        assert!(free(&map).is_empty());
+        let data = map.partition_data(idx).unwrap();
+        assert_eq!(data[..20*512], pattern(20, 1)[..]);
+        assert!(data[20*512..].iter().all(|b| *b == 0));
+
+        let first = map.resize_partition(1, 60).unwrap();
+        assert_eq!(map.partition_entry(first).unwrap().length(), 60);
+        assert!(matches!(map.resize_partition(first, 901), Err(ApmError::Overlap(160, 1001))));
+        let first = map.resize_partition(first, 10).unwrap();
+        assert_eq!(map.partition_data(first).unwrap(), pattern(10, 0));
+    }
+
+    #[test]
+    fn grow_volume_updates_mdb_and_bitmap() {
+        let mut map = ApmMap::new(3136);
+        let mut volume = vec![0; 3072*512];
+        hfs::format_blank(&mut volume, "Grow").unwrap();
+        let entry = PartitionEntry::new()
+            .with_start(64)
+            .with_length(3072)
+            .with_name("MacOS")
+            .with_type("Apple_HFS");
+        map.insert_partition(entry, &volume).unwrap();
+        let be16 = |data: &[u8], offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+        let old_free = be16(&volume, 1024 + 34);
+
+        let (idx, old) = map.resize_device(64 + 4096, true).unwrap().unwrap();
+        map.grow_volume(idx, old).unwrap();
+        let data = map.partition_data(idx).unwrap();
+        let mdb = &data[1024..1536];
+        // drNmAlBlks: the bitmap of a single block describes 4096 allocation blocks, of which
+        // the space before them and the alternate MDB leave 4090
+        assert_eq!(be16(mdb, 18), 4090);
+        assert_eq!(be16(mdb, 34), old_free + 4090 - 3066);
+        let bitmap = &data[be16(mdb, 14) as usize*512..][..512];
+        let used = (0..4090).filter(|n| bitmap[n/8] & (0x80 >> (n % 8)) != 0).count();
+        assert_eq!(used, 4090 - be16(mdb, 34) as usize);
+        assert!((3066..4090).all(|n| bitmap[n/8] & (0x80 >> (n % 8)) == 0));
+        assert_eq!(data[(4096 - 2)*512..][..512], *mdb);
+        assert!(data[(3072 - 2)*512..][..512].iter().all(|b| *b == 0));
+        assert_eq!(hfs::Volume::open(Cursor::new(&data)).unwrap().name(), "Grow");
+
+        assert!(matches!(map.grow_volume(idx, 4096), Err(ApmError::Hfs(_))));
+    }
+
+    #[test]
+    fn grow_volume_moves_data_for_a_larger_bitmap() {
+        let file = hfs::File { name: "Data".to_string(), data: pattern(100, 3), ..hfs::File::default() };
+        let root = hfs::Folder { files: vec![file], ..hfs::Folder::default() };
+        let grow = |blocks: u32| {
+            let mut map = ApmMap::new(64 + blocks*16);
+            let mut volume = vec![0; blocks as usize*512];
+            hfs::format(&mut volume, "Grow", &root).unwrap();
+            let entry = PartitionEntry::new()
+                .with_start(64)
+                .with_length(blocks)
+                .with_name("MacOS")
+                .with_type("Apple_HFS");
+            map.insert_partition(entry, &volume).unwrap();
+            let (idx, old) = map.resize_device(64 + blocks*16, true).unwrap().unwrap();
+            let unused = map.grow_volume(idx, old).unwrap();
+            (map.partition_data(idx).unwrap().to_vec(), unused)
+        };
+        let be16 = |data: &[u8], offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+
+        // The bitmap of a 1 MiB volume describes 4096 allocation blocks, 16 MiB need 8 blocks
+        let (data, unused) = grow(2048);
+        assert_eq!(unused, 0);
+        let mdb = &data[1024..1536];
+        assert_eq!((be16(mdb, 14), be16(mdb, 28)), (3, 11));
+        assert_eq!(be16(mdb, 18), 32768 - 2 - 11);
+        let mut volume = hfs::Volume::open(Cursor::new(&data)).unwrap();
+        let hfs::EntryKind::File(info) = volume.lookup("Data").unwrap().kind else {
+            panic!("Data is not a file");
+        };
+        assert_eq!(volume.read_fork(&info, hfs::Fork::Data).unwrap(), pattern(100, 3));
+        let bitmap = &data[3*512..11*512];
+        let free = (0..32755).filter(|n| bitmap[n/8] & (0x80 >> (n % 8)) == 0).count();
+        assert_eq!(free, be16(mdb, 34) as usize);
 
-        Ok(&self.raw_data)
+        // With 512-byte allocation blocks, HFS ends at 65535 of them
+        let (data, unused) = grow(8192);
+        let mdb = &data[1024..1536];
+        assert_eq!((be16(mdb, 18), be16(mdb, 28)), (65535, 19));
+        assert_eq!(unused, (131072 - 2 - 19 - 65535)*512);
+        assert_eq!(hfs::Volume::open(Cursor::new(&data)).unwrap().name(), "Grow");
     }
 }
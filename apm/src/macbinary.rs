@@ -0,0 +1,97 @@
+//! MacBinary files, which pack both forks and Finder information of a Mac OS file into one
+
+use crate::hfs::File;
+use crate::macroman;
+
+const HEADER_LEN: usize = 128;
+
+fn be16(d: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([d[off], d[off + 1]])
+}
+
+fn be32(d: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes(d[off..off + 4].try_into().unwrap())
+}
+
+/// CRC-16/XMODEM, as used by MacBinary II and later
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Decodes a MacBinary I, II or III file
+pub fn decode(bytes: &[u8]) -> Option<File> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let name_len = header[1] as usize;
+    if header[0] != 0 || header[74] != 0 || header[82] != 0 || !(1..=63).contains(&name_len) {
+        return None;
+    }
+    let data_len = be32(header, 83) as usize;
+    let rsrc_len = be32(header, 87) as usize;
+    let newer = &header[102..106] == b"mBIN" || crc16(&header[..124]) == be16(header, 124);
+    if !newer && header[99..126].iter().any(|b| *b != 0) {
+        return None;
+    }
+    let secondary = if newer { be16(header, 120) as usize } else { 0 };
+    let data_start = HEADER_LEN + secondary.next_multiple_of(128);
+    let rsrc_start = data_start + data_len.next_multiple_of(128);
+    // Without a checksum, the lengths are all there is to tell a MacBinary file apart
+    if !newer && bytes.len() != rsrc_start + rsrc_len.next_multiple_of(128) {
+        return None;
+    }
+
+    let mut finder_info = [0u8; 32];
+    // Type, creator, flags, location and folder
+    finder_info[..8].copy_from_slice(&header[65..73]);
+    finder_info[8] = header[73];
+    finder_info[9] = if newer { header[101] } else { 0 };
+    finder_info[10..16].copy_from_slice(&header[75..81]);
+    Some(File {
+        name: macroman::decode(&header[2..2 + name_len]),
+        data: bytes.get(data_start..)?.get(..data_len)?.to_vec(),
+        rsrc: bytes.get(rsrc_start..)?.get(..rsrc_len)?.to_vec(),
+        finder_info,
+        create_date: be32(header, 91),
+        modify_date: be32(header, 95),
+    })
+}
+
+/// Encodes a file as MacBinary III, which MacBinary II readers understand as well. Characters
+/// of the name that MacRoman lacks are replaced with '?'.
+pub fn encode(file: &File) -> Vec<u8> {
+    let mut name: Vec<u8> = file.name.chars()
+        .map(|c| macroman::encode_char(c).unwrap_or(b'?'))
+        .collect();
+    name.truncate(63);
+
+    let mut header = [0u8; HEADER_LEN];
+    header[1] = name.len() as u8;
+    header[2..2 + name.len()].copy_from_slice(&name);
+    header[65..73].copy_from_slice(&file.finder_info[..8]);
+    header[73] = file.finder_info[8];
+    header[75..81].copy_from_slice(&file.finder_info[10..16]);
+    header[83..87].copy_from_slice(&(file.data.len() as u32).to_be_bytes());
+    header[87..91].copy_from_slice(&(file.rsrc.len() as u32).to_be_bytes());
+    header[91..95].copy_from_slice(&file.create_date.to_be_bytes());
+    header[95..99].copy_from_slice(&file.modify_date.to_be_bytes());
+    header[101] = file.finder_info[9];
+    header[102..106].copy_from_slice(b"mBIN");
+    // Version written and version needed to read
+    header[122] = 130;
+    header[123] = 129;
+    let crc = crc16(&header[..124]);
+    header[124..126].copy_from_slice(&crc.to_be_bytes());
+
+    let mut ret = header.to_vec();
+    for fork in [&file.data, &file.rsrc] {
+        ret.extend_from_slice(fork);
+        ret.resize(ret.len().next_multiple_of(128), 0);
+    }
+    ret
+}
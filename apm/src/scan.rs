@@ -0,0 +1,321 @@
+//! Recovery of partitions from disks whose driver descriptor or partition map was overwritten
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use deku::prelude::*;
+
+use crate::detect::{self, Detected, Filesystem};
+use crate::device::Device;
+use crate::{ApmError, ApmMap, DriverData, DriverDescriptorBlock, DriverType, PartitionEntry, Storage};
+
+/// Number of blocks read from the device at once
+const CHUNK: u32 = 2048;
+
+/// A partition found by `scan`
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    /// First block of the partition
+    pub start: u32,
+    /// Length in blocks
+    pub length: u32,
+    /// Partition map entry describing the partition, with the block it was found in
+    pub entry: Option<(u32, PartitionEntry)>,
+    /// Filesystem found at the start of the partition
+    pub filesystem: Option<Detected>,
+    /// The map entry disagrees with itself or reaches past the end of the disk, so its length
+    /// was cut down and it only counts where nothing better was found
+    pub weak: bool,
+}
+
+impl Candidate {
+    pub fn end(&self) -> u32 {
+        self.start + self.length
+    }
+    fn overlaps(&self, other: &Candidate) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+/// Filesystems whose superblocks are distinctive enough to be looked for in every block
+fn superblock(data: &[u8]) -> Option<Detected> {
+    let found = detect::detect(data)?;
+    let plausible = match found.fs {
+        Filesystem::Mfs | Filesystem::Hfs | Filesystem::HfsWrapper =>
+            found.block_size >= 512 && found.block_size % 512 == 0,
+        Filesystem::HfsPlus | Filesystem::Hfsx =>
+            found.block_size >= 512 && found.block_size.is_power_of_two(),
+        Filesystem::Ext2 | Filesystem::Ext3 | Filesystem::Ext4 => found.block_size <= 65536,
+        Filesystem::Ufs => found.block_size >= 512 && found.block_size.is_power_of_two(),
+        _ => false,
+    };
+    (plausible && found.size >= 512).then_some(found)
+}
+
+/// Whether volumes of `fs` keep a copy of their header at the end of their partition
+fn has_alternate(fs: Filesystem) -> bool {
+    matches!(fs, Filesystem::Mfs | Filesystem::Hfs | Filesystem::HfsWrapper | Filesystem::HfsPlus | Filesystem::Hfsx)
+}
+
+/// First block after `volume` where another filesystem may start: past the map entry
+/// describing a partition starting with it, and past the last allocation unit the partition
+/// may have beyond the volume
+fn reach(volume: &Candidate, entries: &[Candidate]) -> u32 {
+    let slack = volume.filesystem.as_ref().map_or(0, |f| f.block_size.div_ceil(512).min(u32::MAX as u64) as u32);
+    entries.iter()
+        .filter(|c| c.start == volume.start)
+        .map(Candidate::end)
+        .fold(volume.end().saturating_add(slack), u32::max)
+}
+
+/// Searches every block of a disk for partition map entries and filesystems, reading it piece
+/// by piece.
+///
+/// Map entries are listed once per range they describe, filesystems are only looked for
+/// outside of filesystems found before them and the partitions holding those. Alternate
+/// headers of HFS volumes are skipped. Candidates are sorted by their first block.
+pub fn scan(device: &Device) -> Result<Vec<Candidate>, ApmError> {
+    let blocks = (device.size()/512).min(u32::MAX as u64) as u32;
+    let mut entries: Vec<Candidate> = Vec::new();
+    let mut volumes: Vec<Candidate> = Vec::new();
+    let mut window = Vec::new();
+    let mut alternate_found = false;
+    for block in 0..blocks {
+        // Each window reaches far enough past its last block for superblocks starting there
+        let chunk = block - block % CHUNK;
+        if block == chunk {
+            let end = (chunk as u64 + CHUNK as u64)*512 + detect::PROBE_LEN as u64;
+            window.resize((end.min(blocks as u64*512) - chunk as u64*512) as usize, 0);
+            device.read_at(chunk as u64*512, &mut window)?;
+        }
+        let data = &window[(block - chunk) as usize*512..];
+        let bytes = &data[..512];
+        if bytes.starts_with(b"PM") {
+            if let Ok((_, entry)) = PartitionEntry::from_bytes((bytes, 0)) {
+                let start = entry.start();
+                let length = entry.length().min(blocks.saturating_sub(start));
+                let weak = length != entry.length() || entry.data_size() != entry.length();
+                if length > 0 && !entries.iter().any(|c| c.start == start && c.length == length) {
+                    entries.push(Candidate { start, length, entry: Some((block, entry)), filesystem: None, weak });
+                }
+            }
+        }
+
+        if volumes.last().is_some_and(|v| block < reach(v, &entries)) {
+            continue;
+        }
+        if let Some(found) = superblock(data) {
+            // HFS keeps a copy of its header at the end of the partition, which may lie far past
+            // the end of a volume that could not grow to fill it
+            let last = volumes.last().and_then(|v| v.filesystem.as_ref());
+            if !alternate_found && last == Some(&found) && has_alternate(found.fs) {
+                alternate_found = true;
+                continue;
+            }
+            let length = found.size.div_ceil(512).min((blocks - block) as u64) as u32;
+            volumes.push(Candidate { start: block, length, entry: None, filesystem: Some(found), weak: false });
+            alternate_found = false;
+        }
+    }
+
+    // Volumes described by a map entry are merged into it
+    for volume in volumes {
+        match entries.iter_mut().find(|c| c.start == volume.start) {
+            Some(c) if c.weak => {
+                // The filesystem knows its size better than a damaged entry
+                c.length = volume.length;
+                c.filesystem = volume.filesystem;
+            },
+            Some(c) => c.filesystem = volume.filesystem,
+            None => entries.push(volume),
+        }
+    }
+    entries.sort_by_key(|c| (c.start, c.entry.is_none()));
+    Ok(entries)
+}
+
+/// Builds a new driver descriptor and partition map for the partitions in `candidates` found on
+/// `device`. Only the DDM and the map are written by the next `flush`, partition contents stay
+/// where they are.
+///
+/// Map entries and empty space found by the scan are skipped, as are candidates overlapping
+/// earlier ones and weak candidates overlapping any other. Driver partitions are listed in the
+/// driver descriptor again, and `Apple_Free` entries describe the space between partitions.
+pub fn rebuild(device: Device, candidates: &[Candidate]) -> Result<ApmMap, ApmError> {
+    let blocks = (device.size()/512).min(u32::MAX as u64) as u32;
+    let mut chosen: Vec<&Candidate> = Vec::new();
+    for c in candidates.iter().filter(|c| !c.weak).chain(candidates.iter().filter(|c| c.weak)) {
+        let ty = c.entry.as_ref().map(|(_, e)| e.part_type());
+        if matches!(ty, Some("Apple_partition_map" | "Apple_Free")) || c.start < 2 {
+            continue;
+        }
+        if !chosen.iter().any(|other| other.overlaps(c)) {
+            chosen.push(c);
+        }
+    }
+    chosen.sort_by_key(|c| c.start);
+
+    // The new map fills the space before the first partition, like the old one most likely did
+    let map_len = chosen.first().map(|c| c.start - 1).unwrap_or(0x3f).min(0x3f);
+    if (map_len as usize) < chosen.len() + 1 {
+        return Err(ApmError::MapFull);
+    }
+    let mut map = ApmMap {
+        update_driver_desc: true,
+        driver_desc: DriverDescriptorBlock::default().with_blk_count(blocks),
+        update_partition_table: true,
+        partitions: vec![
+            PartitionEntry::new()
+                .with_start(1)
+                .with_length(map_len)
+                .with_name("Apple")
+                .with_type("Apple_partition_map"),
+        ],
+        storage: Storage::Device(Arc::new(device), BTreeMap::new(), blocks),
+        dirty: Vec::new(),
+    };
+
+    for c in chosen {
+        let entry = match &c.entry {
+            Some((_, entry)) if c.weak => entry.clone().with_length(c.length),
+            Some((_, entry)) => entry.clone(),
+            None => PartitionEntry::new()
+                .with_start(c.start)
+                .with_length(c.length)
+                .with_name(c.filesystem.as_ref().and_then(|f| f.name.clone()).unwrap_or("MacOS".to_string()))
+                .with_type(match c.filesystem.as_ref().map(|f| f.fs) {
+                    Some(fs) => fs.partition_types()[0],
+                    None => "Apple_HFS",
+                }),
+        };
+        entry.validate()?;
+        map.partitions.push(entry.clone());
+
+        if entry.part_type().starts_with("Apple_Driver") {
+            let ty = match (entry.part_type(), entry.proc_type()) {
+                (_, "PowerPC") => DriverType::MacOsPpc,
+                ("Apple_Driver_ATA", _) => DriverType::MacOsAta,
+                _ => DriverType::MacOs68k,
+            };
+            let size = match entry.boot_size() {
+                0 => c.length,
+                bytes => bytes.div_ceil(512),
+            };
+            map.driver_desc.push_driver_data(DriverData::new(c.start, size.min(u16::MAX as u32) as u16, ty));
+        }
+    }
+    map.list_free_space();
+    map.update_partition_count();
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::{hfs, AllocPolicy, PartitionMap};
+
+    /// An image with a driver, an HFS volume starting just before a chunk boundary and a
+    /// partition holding no filesystem
+    fn image() -> Vec<u8> {
+        let mut map = ApmMap::new(8192);
+        let driver: Vec<u8> = (0..20*512).map(|i| (i % 253) as u8 + 1).collect();
+        map.push_driver(DriverType::MacOs68k, &driver, AllocPolicy::new()).unwrap();
+        let mut volume = vec![0; 2048*512];
+        hfs::format_blank(&mut volume, "Lost").unwrap();
+        let hfs = PartitionEntry::new().with_start(2040).with_length(2048).with_name("MacOS").with_type("Apple_HFS");
+        map.insert_partition(hfs, &volume).unwrap();
+        let scratch = PartitionEntry::new().with_start(6000).with_length(100).with_name("Raw").with_type("Apple_Scratch");
+        map.insert_partition(scratch, &[0x5a; 100*512]).unwrap();
+        let mut out = std::io::Cursor::new(Vec::new());
+        map.flush(&mut out).unwrap();
+        out.into_inner()
+    }
+
+    /// Scans and rebuilds `data` in a file, returning what the file holds afterwards
+    fn rebuild_file(data: &[u8]) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("apm-scan-{}-{}.img", std::process::id(), data[512]));
+        fs::write(&path, data).unwrap();
+        let candidates = scan(&Device::open(&path, false).unwrap()).unwrap();
+        let mut map = rebuild(Device::open(&path, false).unwrap(), &candidates).unwrap();
+        map.flush(&mut Device::open(&path, true).unwrap()).unwrap();
+        let ret = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        ret
+    }
+
+    fn layout(map: &ApmMap) -> Vec<(u32, u32, String)> {
+        map.partitions()
+            .map(|p| (p.start(), p.length(), p.part_type().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn rebuild_after_losing_the_ddm() {
+        let original = image();
+        let mut damaged = original.clone();
+        damaged[..512].fill(0);
+        let rebuilt = rebuild_file(&damaged);
+
+        // Only the DDM and the map are written
+        let map_end = 64*512;
+        assert_eq!(rebuilt[map_end..], original[map_end..]);
+        let (old, new) = (ApmMap::decode(original).unwrap(), ApmMap::decode(rebuilt).unwrap());
+        let used = |map: &ApmMap| layout(map).into_iter().filter(|p| p.2 != "Apple_Free").collect::<Vec<_>>();
+        assert_eq!(used(&new), used(&old));
+        let free: Vec<(u32, u32)> = new.partitions()
+            .filter(|p| p.part_type() == "Apple_Free")
+            .map(|p| (p.start(), p.start() + p.length()))
+            .collect();
+        assert_eq!(free, [(84, 2040), (4088, 6000), (6100, 8192)]);
+        let drivers: Vec<(u32, u16, DriverType)> = new.drivers().map(|d| (d.start(), d.size(), d.driver_type())).collect();
+        assert_eq!(drivers, [(64, 20, DriverType::MacOs68k)]);
+        assert_eq!(new.blk_count(), 8192);
+    }
+
+    #[test]
+    fn rebuild_after_losing_the_map() {
+        let original = image();
+        let mut damaged = original.clone();
+        damaged[..64*512].fill(0);
+        let rebuilt = rebuild_file(&damaged);
+
+        // Without map entries, only the volume can be found
+        let new = ApmMap::decode(rebuilt).unwrap();
+        let free = |start: u32, length: u32| (start, length, "Apple_Free".to_string());
+        assert_eq!(layout(&new)[1..], [
+            free(64, 1976),
+            (2040, 2048, "Apple_HFS".to_string()),
+            free(4088, 4104),
+        ]);
+        assert_eq!(new.partition_entry(2).unwrap().name(), "Lost");
+    }
+
+    #[test]
+    fn scan_skips_alternate_headers_past_the_volume() {
+        // A volume filling half of its partition, with its alternate MDB at the end of the
+        // partition, as left behind by growing a volume beyond what HFS allows
+        let mut data = image();
+        let mut volume = vec![0; 1000*512];
+        hfs::format_blank(&mut volume, "Half").unwrap();
+        let start = 2040*512;
+        data[start..][..2048*512].fill(0);
+        data[start..][..volume.len()].copy_from_slice(&volume);
+        let mdb = volume[1024..1536].to_vec();
+        data[start + (2048 - 2)*512..][..512].copy_from_slice(&mdb);
+
+        let path = std::env::temp_dir().join(format!("apm-scan-{}-half.img", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let found = scan(&Device::open(&path, false).unwrap());
+        data[..64*512].fill(0);
+        fs::write(&path, &data).unwrap();
+        let found_unmapped = scan(&Device::open(&path, false).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let volumes = |candidates: Vec<Candidate>| candidates.into_iter()
+            .filter(|c| c.filesystem.is_some())
+            .map(|c| (c.start, c.length, c.entry.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(volumes(found.unwrap()), [(2040, 2048, true)]);
+        assert_eq!(volumes(found_unmapped.unwrap()), [(2040, 1000, false)]);
+    }
+}
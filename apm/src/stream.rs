@@ -0,0 +1,180 @@
+//! Streaming access to partition contents, for partitions too large to copy around whole
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::ApmMap;
+
+/// New offset for a seek in a partition of `len` bytes currently at `pos`
+fn seek(pos: u64, len: u64, to: SeekFrom) -> io::Result<u64> {
+    let new = match to {
+        SeekFrom::Start(p) => Some(p),
+        SeekFrom::End(d) => len.checked_add_signed(d),
+        SeekFrom::Current(d) => pos.checked_add_signed(d),
+    };
+    new.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the partition"))
+}
+
+/// Reads the contents of a partition, see `ApmMap::partition_reader`
+pub struct PartitionReader<'a> {
+    map: &'a ApmMap,
+    /// First byte of the partition on the device
+    offset: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a> PartitionReader<'a> {
+    pub(crate) fn new(map: &'a ApmMap, start: u32, length: u32) -> Self {
+        Self { map, offset: start as u64*512, len: length as u64*512, pos: 0 }
+    }
+    /// Length of the partition in bytes
+    pub fn len(&self) -> u64 { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+}
+
+impl Read for PartitionReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        self.map.storage.read_at(self.offset + self.pos, &mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for PartitionReader<'_> {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        self.pos = seek(self.pos, self.len, to)?;
+        Ok(self.pos)
+    }
+}
+
+/// Reads and writes the contents of a partition, see `ApmMap::partition_writer`
+pub struct PartitionWriter<'a> {
+    map: &'a mut ApmMap,
+    /// First byte of the partition on the device
+    offset: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a> PartitionWriter<'a> {
+    pub(crate) fn new(map: &'a mut ApmMap, start: u32, length: u32) -> Self {
+        Self { map, offset: start as u64*512, len: length as u64*512, pos: 0 }
+    }
+    /// Length of the partition in bytes
+    pub fn len(&self) -> u64 { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+}
+
+impl Read for PartitionWriter<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        self.map.storage.read_at(self.offset + self.pos, &mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for PartitionWriter<'_> {
+    /// Writes up to the end of the partition, after which nothing more fits
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        self.map.write_at(self.offset + self.pos, &buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+    /// Does nothing, written data is kept by the map until `ApmMap::flush`
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PartitionWriter<'_> {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        self.pos = seek(self.pos, self.len, to)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use crate::device::Device;
+    use crate::PartitionEntry;
+    use super::*;
+
+    /// A map of 256 blocks with a partition of 8 blocks at block 100, filled with its offsets
+    fn map() -> ApmMap {
+        let mut map = ApmMap::new(256);
+        let data: Vec<u8> = (0..8*512).map(|i| (i/7) as u8).collect();
+        let entry = PartitionEntry::new().with_start(100).with_length(8).with_type("Apple_HFS");
+        map.insert_partition(entry, &data).unwrap();
+        map
+    }
+
+    #[test]
+    fn seek_past_the_end() {
+        let mut map = map();
+        let mut reader = map.partition_reader(1).unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(100)).unwrap(), 4196);
+        assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-5000)).is_err());
+        reader.seek(SeekFrom::End(-4)).unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, [(4092/7) as u8, (4093/7) as u8, (4094/7) as u8, (4095/7) as u8]);
+
+        let mut writer = map.partition_writer(1).unwrap();
+        writer.seek(SeekFrom::Start(5000)).unwrap();
+        assert_eq!(writer.write(&[1; 16]).unwrap(), 0);
+        assert_eq!(writer.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn writes_stop_at_the_end() {
+        let mut map = map();
+        let mut writer = map.partition_writer(1).unwrap();
+        writer.seek(SeekFrom::End(-10)).unwrap();
+        assert_eq!(writer.write(&[0xee; 16]).unwrap(), 10);
+        writer.seek(SeekFrom::End(-10)).unwrap();
+        let err = writer.write_all(&[0xee; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        // Nothing reached the next partition
+        assert_eq!(&map.read_blocks(100, 8).unwrap()[4086..], [0xee; 10]);
+        assert_eq!(map.read_blocks(108, 1).unwrap()[..], [0; 512]);
+    }
+
+    #[test]
+    fn read_pending_writes_from_a_device() {
+        let path = std::env::temp_dir().join(format!("apm-stream-{}.img", std::process::id()));
+        let mut image = Cursor::new(vec![0; 256*512]);
+        map().flush(&mut image).unwrap();
+        let old = image.into_inner();
+        fs::write(&path, &old).unwrap();
+
+        let mut map = ApmMap::open(Device::open(&path, true).unwrap()).unwrap();
+        let mut writer = map.partition_writer(1).unwrap();
+        writer.seek(SeekFrom::Start(1000)).unwrap();
+        writer.write_all(&[0xaa; 100]).unwrap();
+        writer.seek(SeekFrom::Start(3000)).unwrap();
+        writer.write_all(&[0xbb; 600]).unwrap();
+
+        let mut expected = old[100*512..108*512].to_vec();
+        expected[1000..1100].fill(0xaa);
+        expected[3000..3600].fill(0xbb);
+        let mut reader = map.partition_reader(1).unwrap();
+        reader.seek(SeekFrom::Start(900)).unwrap();
+        let mut buf = vec![0; 2800];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected[900..3700]);
+        // Only flushing writes to the device
+        assert_eq!(fs::read(&path).unwrap(), old);
+
+        let mut device = Device::open(&path, true).unwrap();
+        map.flush(&mut device).unwrap();
+        drop(device);
+        assert_eq!(fs::read(&path).unwrap()[100*512..108*512], expected);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! Read-only access to partition maps, and to images that are already in memory
+
+use crate::{ApmError, ApmMap, DriverData, DriverDescriptorBlock, PartitionEntry, Storage};
+
+/// Queries shared by `ApmMap` and `ApmMapRef`, answered from the DDM and partition map alone
+pub trait PartitionMap {
+    fn driver_desc(&self) -> &DriverDescriptorBlock;
+    /// All map entries, in the order they are stored in
+    fn entries(&self) -> &[PartitionEntry];
+    /// Number of blocks the image or device holds, which `blk_count` may not agree with
+    fn device_blocks(&self) -> u32;
+
+    fn block_size(&self) -> u16 { self.driver_desc().block_size }
+    fn blk_count(&self) -> u32 { self.driver_desc().blk_count }
+    fn dev_type(&self) -> u16 { self.driver_desc().dev_type }
+    fn dev_id(&self) -> u16 { self.driver_desc().dev_id }
+    fn data(&self) -> u32 { self.driver_desc().data }
+    fn drivers(&self) -> impl Iterator<Item = &DriverData> {
+        self.driver_desc().drivers.iter()
+    }
+    /// Finds the partition holding a driver registered in the DDM
+    fn driver_partition(&self, num: usize) -> Option<usize> {
+        let driver = self.driver_desc().drivers.get(num)?;
+        self.entries().iter()
+            .position(|p| p.start == driver.start && p.part_type() != "Apple_Free")
+    }
+    fn partitions(&self) -> impl Iterator<Item = &PartitionEntry> {
+        self.entries().iter()
+    }
+    fn partitions_used(&self) -> impl Iterator<Item = &PartitionEntry> {
+        self.partitions()
+            .filter(|p| p.part_type() != "Apple_Free")
+    }
+    fn partition_entry(&self, idx: usize) -> Option<&PartitionEntry> {
+        self.entries().get(idx)
+    }
+    /// Checks that `count` blocks starting at `start` lie within the device, returning the
+    /// block after them
+    fn block_range_end(&self, start: u32, count: u32) -> Result<u32, ApmError> {
+        start.checked_add(count)
+            .filter(|end| *end <= self.device_blocks())
+            .ok_or(ApmError::OutOfBounds(start, start.saturating_add(count)))
+    }
+    /// First block and length of a driver registered in the DDM
+    fn driver_extent(&self, num: usize) -> Result<(u32, u32), ApmError> {
+        let driver = self.driver_desc().drivers.get(num)
+            .ok_or(ApmError::NoDriver(num))?;
+        Ok((driver.start, driver.size as u32))
+    }
+    /// First block and length of a partition, checked against the size of the device
+    fn partition_extent(&self, idx: usize) -> Result<(u32, u32), ApmError> {
+        let p = self.entries().get(idx)
+            .ok_or(ApmError::NoPartition(idx))?;
+        self.block_range_end(p.start, p.length)?;
+        Ok((p.start, p.length))
+    }
+}
+
+/// A partition map parsed from a borrowed image, such as a memory-mapped file. Unlike `ApmMap`,
+/// it never copies the image.
+#[derive(Clone, Debug)]
+pub struct ApmMapRef<'a> {
+    driver_desc: DriverDescriptorBlock,
+    partitions: Vec<PartitionEntry>,
+    data: &'a [u8],
+}
+
+impl PartitionMap for ApmMapRef<'_> {
+    fn driver_desc(&self) -> &DriverDescriptorBlock {
+        &self.driver_desc
+    }
+    fn entries(&self) -> &[PartitionEntry] {
+        &self.partitions
+    }
+    fn device_blocks(&self) -> u32 {
+        (self.data.len()/512).min(u32::MAX as usize) as u32
+    }
+}
+
+impl<'a> ApmMapRef<'a> {
+    pub fn decode(data: &'a [u8]) -> Result<Self, ApmError> {
+        let (driver_desc, partitions) = ApmMap::parse(data)?;
+        Ok(Self { driver_desc, partitions, data })
+    }
+    /// `count` blocks starting at `start`
+    pub fn read_blocks(&self, start: u32, count: u32) -> Result<&'a [u8], ApmError> {
+        let end = self.block_range_end(start, count)?;
+        Ok(&self.data[start as usize*512..end as usize*512])
+    }
+    /// Blocks of a driver registered in the DDM
+    pub fn driver_bytes(&self, num: usize) -> Result<&'a [u8], ApmError> {
+        let (start, size) = self.driver_extent(num)?;
+        self.read_blocks(start, size)
+    }
+    pub fn partition_data(&self, idx: usize) -> Result<&'a [u8], ApmError> {
+        let (start, length) = self.partition_extent(idx)?;
+        self.read_blocks(start, length)
+    }
+    /// The whole image
+    pub fn raw(&self) -> &'a [u8] {
+        self.data
+    }
+    /// Copies the image into a map that can be changed
+    pub fn to_map(&self) -> ApmMap {
+        ApmMap {
+            update_driver_desc: false,
+            driver_desc: self.driver_desc.clone(),
+            update_partition_table: false,
+            partitions: self.partitions.clone(),
+            storage: Storage::Memory(self.data.to_vec()),
+            dirty: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::{AllocPolicy, DriverType};
+    use super::*;
+
+    #[test]
+    fn map_and_ref_agree() {
+        let mut map = ApmMap::new(2048);
+        map.push_partition("First", "Apple_HFS", &[0x11; 40*512], AllocPolicy::new()).unwrap();
+        let entry = PartitionEntry::new().with_name("Driver").with_type(DriverType::MacOs68k.partition_type());
+        map.push_driver_partition(DriverType::MacOs68k, entry, &[0x22; 3000], 3000, AllocPolicy::new()).unwrap();
+        map.push_partition("Last", "Apple_UNIX_SVR2", &[0x33; 100], AllocPolicy::new()).unwrap();
+        map.list_free_space();
+        let mut image = Cursor::new(vec![0; 2048*512]);
+        map.flush(&mut image).unwrap();
+        let image = image.into_inner();
+
+        let map = ApmMap::decode(image.clone()).unwrap();
+        let view = ApmMapRef::decode(&image).unwrap();
+        // Neither the DDM nor map entries can be compared directly
+        let debug = |value: &dyn std::fmt::Debug| format!("{:?}", value);
+        assert_eq!(debug(map.driver_desc()), debug(view.driver_desc()));
+        assert_eq!(debug(&map.entries()), debug(&view.entries()));
+        assert!(map.partitions().count() > map.partitions_used().count());
+        assert_eq!(debug(&map.partitions_used().collect::<Vec<_>>()), debug(&view.partitions_used().collect::<Vec<_>>()));
+        assert_eq!(debug(&map.drivers().collect::<Vec<_>>()), debug(&view.drivers().collect::<Vec<_>>()));
+        assert_eq!((map.block_size(), map.blk_count(), map.device_blocks()), (view.block_size(), view.blk_count(), view.device_blocks()));
+        assert_eq!((map.dev_type(), map.dev_id(), map.data()), (view.dev_type(), view.dev_id(), view.data()));
+        assert_eq!(map.driver_partition(0), Some(2));
+        assert_eq!(map.driver_partition(0), view.driver_partition(0));
+        assert_eq!(*map.driver_bytes(0).unwrap(), *view.driver_bytes(0).unwrap());
+        for idx in 0..map.entries().len() + 1 {
+            assert_eq!(debug(&map.partition_entry(idx)), debug(&view.partition_entry(idx)));
+            assert_eq!(map.partition_data(idx).ok().as_deref(), view.partition_data(idx).ok());
+        }
+        assert!(matches!(view.driver_bytes(1), Err(ApmError::NoDriver(1))));
+        assert!(matches!(view.read_blocks(2000, 100), Err(ApmError::OutOfBounds(2000, 2100))));
+        assert_eq!(*view.to_map().raw().unwrap(), image);
+    }
+}
@@ -1,13 +1,22 @@
 use std::fs;
-use std::path::PathBuf;
-use anyhow::{Context, Result, anyhow};
-use clap::{Subcommand, Parser};
-use apm::{ApmMap};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{ArgGroup, Args, Subcommand, Parser, ValueEnum};
+use apm::{AllocPolicy, ApmMap, DriverType, Fit, PartitionEntry, PartitionMap, Strategy};
+use apm::{detect, macroman, scan};
+use apm::device::{self, Device};
+use apm::hfs::{self, EntryKind, ExportFormat, Fork};
+use apm::ident::{self, KnownDriver, Match};
 
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
     op: Cmd,
+    /// Where to save the old driver descriptor and partition map before changing them. Always
+    /// done for devices, defaulting to a file in the current directory.
+    #[arg(long, global = true)]
+    backup: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Clone)]
@@ -27,7 +36,17 @@ enum Cmd {
         /// Path to partition data
         data: PathBuf,
         /// Number of partition as identified using 'print' subcommand
-        num: u8
+        num: u8,
+        /// Fill the rest of the partition with zeroes if the data is shorter than it
+        #[arg(long, conflicts_with = "truncate")]
+        pad: bool,
+        /// Cut the data at the end of the partition if it is longer than it, the number of bytes
+        /// dropped is printed
+        #[arg(long, conflicts_with = "grow")]
+        truncate: bool,
+        /// Grow the partition into the free space after it if the data is longer than it
+        #[arg(long)]
+        grow: bool,
     },
     /// Saves a partition data to a file
     DumpPartition {
@@ -57,53 +76,469 @@ enum Cmd {
         #[arg(short)]
         /// Path to driver data, will be inserted in order
         driver: Vec<PathBuf>,
-        #[arg(long)]
-        /// cursed
+        #[arg(short = 't', long)]
+        /// Type of each driver given with -d, in order, defaults to mac68k
+        driver_type: Vec<DriverType>,
+        #[arg(long, conflicts_with = "driver43_from", requires = "driver43_size")]
+        /// Path to an Apple SCSI driver to install in an Apple_Driver43 partition
         driver43: Option<PathBuf>,
+        /// Length of the driver code in the file given with --driver43. The rest of the file,
+        /// such as padding in a dump of a whole driver partition, is stored after the code.
+        #[arg(long, value_parser = size_binary, requires = "driver43")]
+        driver43_size: Option<u32>,
+        /// Image to copy the first Apple_Driver43 driver from, with its partition
+        #[arg(long)]
+        driver43_from: Option<PathBuf>,
+        /// Directory to copy into a new HFS partition, after all other partitions
+        #[arg(long)]
+        hfs_from_dir: Option<PathBuf>,
+        /// Name of the HFS volume, defaults to the name of the directory
+        #[arg(long, requires = "hfs_from_dir")]
+        volume_name: Option<String>,
+        /// Size of the HFS partition, defaults to all remaining space
+        #[arg(long, value_parser = size_binary, requires = "hfs_from_dir")]
+        hfs_size: Option<u32>,
+        #[command(flatten)]
+        alloc: AllocArgs,
+    },
+    /// Adds a new partition to an existing drive
+    #[command(group(ArgGroup::new("contents").required(true).args(["size", "data"])))]
+    AddPartition {
+        file: PathBuf,
+        /// Name of the new partition
+        #[arg(long)]
+        name: String,
+        /// Type of the new partition, for example 'Apple_HFS'
+        #[arg(long = "type")]
+        ty: String,
+        /// Size of an empty partition, will be rounded up to 512 byte increments
+        #[arg(long, value_parser = size_binary)]
+        size: Option<u32>,
+        /// Path to partition data, sets the size of the partition
+        #[arg(long)]
+        data: Option<PathBuf>,
+        /// First block of the partition, defaults to an empty space picked by --strategy
+        #[arg(long)]
+        start: Option<u32>,
+        #[command(flatten)]
+        alloc: AllocArgs,
     },
+    /// Adds a driver in a new partition and registers it in the driver descriptor map
+    AddDriver {
+        file: PathBuf,
+        /// Path to driver data
+        data: PathBuf,
+        /// Operating system or processor supported by the driver, one of mac68k, aux, prodos,
+        /// mac-ata, ppc, mac-chained or a number
+        #[arg(long = "type", default_value = "mac68k")]
+        ty: DriverType,
+        /// Type of the partition holding the driver, for example 'Apple_Driver43_CD' or
+        /// 'Apple_Patches'. Defaults to the usual one for the driver type.
+        #[arg(long)]
+        partition_type: Option<String>,
+        /// Processor type of the partition holding the driver, defaults to the usual one for the
+        /// driver type
+        #[arg(long)]
+        proc_type: Option<String>,
+        /// Name of the partition holding the driver
+        #[arg(long, default_value = "Macintosh")]
+        name: String,
+        #[command(flatten)]
+        alloc: AllocArgs,
+    },
+    /// Replaces code of an installed driver, growing its partition if needed
+    ReplaceDriver {
+        file: PathBuf,
+        /// Number of driver as identified using 'print' subcommand
+        num: u8,
+        /// Path to driver data
+        data: PathBuf,
+    },
+    /// Removes a driver and frees its partition
+    RemoveDriver {
+        file: PathBuf,
+        /// Number of driver as identified using 'print' subcommand
+        num: u8,
+    },
+    /// Identifies installed drivers by their contents
+    IdentifyDrivers {
+        file: PathBuf,
+        /// Path to a fingerprint database adding to the built-in one, one tab-separated
+        /// 'sha1 vendor product version cpu' per line
+        #[arg(long)]
+        db: Option<PathBuf>,
+    },
+    /// Writes a partition map saved before an earlier change back to the device
+    RestoreMap {
+        file: PathBuf,
+        /// File written by an earlier change, see --backup
+        saved: PathBuf,
+    },
+    /// Searches every block for partition map entries and filesystems, for disks whose map is
+    /// damaged
+    Scan {
+        file: PathBuf,
+    },
+    /// Replaces the driver descriptor and partition map with new ones
+    #[command(group(ArgGroup::new("source").required(true).args(["from_scan"])))]
+    RebuildMap {
+        file: PathBuf,
+        /// Describe the partitions found by the 'scan' subcommand
+        #[arg(long)]
+        from_scan: bool,
+    },
+    /// Writes an empty filesystem into a partition, erasing its contents
+    #[command(group(ArgGroup::new("fs").required(true).args(["hfs"])))]
+    Mkfs {
+        file: PathBuf,
+        /// Number of partition as identified using 'print' subcommand
+        num: u8,
+        /// Create an HFS volume
+        #[arg(long)]
+        hfs: bool,
+        /// Name of the new volume
+        #[arg(long, default_value = "untitled")]
+        name: String,
+    },
+    /// Lists a directory of an HFS or HFS+ partition
+    Ls {
+        file: PathBuf,
+        /// Number of partition as identified using 'print' subcommand
+        num: u8,
+        /// Path of the directory, with components separated by '/'
+        #[arg(default_value = "/")]
+        path: String,
+    },
+    /// Saves a file from an HFS or HFS+ partition
+    Get {
+        file: PathBuf,
+        /// Number of partition as identified using 'print' subcommand
+        num: u8,
+        /// Path of the file, with components separated by '/'
+        path: String,
+        /// Path to save the file to
+        dest: PathBuf,
+        /// Save the resource fork instead of the data fork
+        #[arg(long, conflicts_with = "format")]
+        rsrc: bool,
+        /// How to keep the resource fork and Finder information
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+    },
+    /// Saves a directory of an HFS or HFS+ partition with everything below it
+    Extract {
+        file: PathBuf,
+        /// Number of partition as identified using 'print' subcommand
+        num: u8,
+        /// Directory to save the files to, created if missing
+        dest: PathBuf,
+        /// Path of the directory to save, with components separated by '/'
+        #[arg(long, default_value = "/")]
+        path: String,
+        /// How to keep resource forks and Finder information
+        #[arg(long, value_enum, default_value_t = OutputFormat::Appledouble)]
+        format: OutputFormat,
+    },
+    /// Changes fields of an existing partition map entry
+    EditPartition {
+        file: PathBuf,
+        /// Number of partition as identified using 'print' subcommand
+        num: u8,
+        /// New name of the partition
+        #[arg(long)]
+        name: Option<String>,
+        /// New type of the partition, for example 'Apple_Scratch'
+        #[arg(long = "type")]
+        ty: Option<String>,
+        /// New processor type, for example '68000'
+        #[arg(long)]
+        processor: Option<String>,
+        /// New status flags, decimal or 0x-prefixed hexadecimal
+        #[arg(long, value_parser = parse_u32)]
+        status: Option<u32>,
+    },
+    /// Moves partitions towards the start of the device, leaving the free space at the end
+    Compact {
+        file: PathBuf,
+    },
+    /// Grows or shrinks the image, adding space to a trailing free partition
+    ResizeDisk {
+        file: PathBuf,
+        /// The new size of the image, will be rounded up to 512 byte increments
+        #[arg(value_parser = size_blocks)]
+        size: u32,
+        /// Give the added space to the last partition instead
+        #[arg(long)]
+        grow_last: bool,
+        /// Grow the HFS or HFS+ volume in the last partition along with it
+        #[arg(long, requires = "grow_last")]
+        grow_fs: bool,
+    },
+    /// Changes the length of a partition, growing it into the free space after it
+    ResizePartition {
+        file: PathBuf,
+        /// Number of partition as identified using 'print' subcommand
+        num: u8,
+        /// The new size of the partition, will be rounded up to 512 byte increments
+        #[arg(value_parser = size_blocks)]
+        size: u32,
+        /// Grow the HFS or HFS+ volume in the partition along with it
+        #[arg(long)]
+        grow_fs: bool,
+    },
+}
+
+#[derive(Args, Clone)]
+struct AllocArgs {
+    /// How to pick the empty space for new data
+    #[arg(long, value_enum, default_value_t = AllocStrategy::FirstFit)]
+    strategy: AllocStrategy,
+    /// Alignment of the start of new data, for example 4K or 1M
+    #[arg(long, value_parser = size_binary, default_value = "512")]
+    align: u32,
+}
+
+impl AllocArgs {
+    fn policy(&self) -> AllocPolicy {
+        let strategy = match self.strategy {
+            AllocStrategy::FirstFit => Strategy::FirstFit,
+            AllocStrategy::BestFit => Strategy::BestFit,
+            AllocStrategy::EndOfDisk => Strategy::EndOfDisk,
+        };
+        AllocPolicy::new()
+            .with_strategy(strategy)
+            .with_align(self.align.div_ceil(512))
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum OutputFormat {
+    /// Only the data fork
+    Raw,
+    /// MacBinary III, with a '.bin' suffix when extracting
+    Macbinary,
+    /// AppleSingle, with an '.as' suffix when extracting
+    Applesingle,
+    /// The data fork with a '._' file next to it
+    Appledouble,
+}
+
+impl From<OutputFormat> for ExportFormat {
+    fn from(f: OutputFormat) -> Self {
+        match f {
+            OutputFormat::Raw => ExportFormat::Raw,
+            OutputFormat::Macbinary => ExportFormat::MacBinary,
+            OutputFormat::Applesingle => ExportFormat::AppleSingle,
+            OutputFormat::Appledouble => ExportFormat::AppleDouble,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum AllocStrategy {
+    /// The first space that is large enough
+    FirstFit,
+    /// The smallest space that is large enough
+    BestFit,
+    /// The last space that is large enough, filled from its end
+    EndOfDisk,
+}
+
+#[cfg(unix)]
+fn is_device(file: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(file)
+        .map(|m| m.file_type().is_block_device() || m.file_type().is_char_device())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_device(_file: &Path) -> bool {
+    false
+}
+
+/// Where the undo journal of changes to the device `file` is kept, in the current directory
+fn journal_path(file: &Path) -> Result<PathBuf> {
+    let name = file.file_name()
+        .ok_or(anyhow!("'{}' is not a file", file.display()))?;
+    Ok(PathBuf::from(format!(".{}.apmtool-journal", name.to_string_lossy())))
+}
+
+/// Undoes a change to the device `file` that was interrupted before it was complete
+fn recover(file: &Path) -> Result<()> {
+    if !is_device(file) {
+        return Ok(());
+    }
+    let journal = journal_path(file)?;
+    if journal.exists() {
+        Device::open(file, true)
+            .and_then(|mut out| out.undo(&journal))
+            .context("Failed to undo an interrupted change")?;
+        eprintln!("Undid an interrupted change, using '{}'", journal.display());
+    }
+    Ok(())
+}
+
+/// Modifies `file` through `write` without leaving it half-written.
+///
+/// Image files are copied, the copy is modified and then renamed over the original. Devices are
+/// written in place, after their first `map_blocks` blocks are saved to a backup file. Everything
+/// overwritten on them is saved to an undo journal first, which is written back if `write`
+/// fails, or by the next change after a crash.
+fn write_safely(file: &Path, map_blocks: u32, backup: Option<&Path>, write: impl FnOnce(&mut Device) -> Result<()>) -> Result<()> {
+    let device = is_device(file);
+    recover(file)?;
+    if device || backup.is_some() {
+        let default = PathBuf::from(format!("apm-backup-{}.bin", std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)));
+        let backup = backup.unwrap_or(&default);
+        let mut old = Vec::new();
+        fs::File::open(file)
+            .and_then(|f| f.take(map_blocks as u64*512).read_to_end(&mut old))
+            .context("Failed to read the old partition map")?;
+        fs::write(backup, old)
+            .context("Failed to save the old partition map")?;
+        eprintln!("Saved the old partition map to '{}'", backup.display());
+    }
+
+    if device {
+        let journal = journal_path(file)?;
+        let mut out = Device::open(file, true)
+            .context("Failed to open the device for writing")?;
+        out.start_journal(&journal)
+            .context("Failed to start the undo journal")?;
+        let result = write(&mut out)
+            .and_then(|_| out.end_journal()
+                .context("Failed to update the device"));
+        if result.is_err() {
+            out.undo(&journal)
+                .context("Failed to undo the partial change")?;
+        }
+        return result;
+    }
+
+    // Links are followed, so that the image they point at is replaced rather than them
+    let file = &fs::canonicalize(file)
+        .context("Failed to find the input file")?;
+    let name = file.file_name()
+        .ok_or(anyhow!("'{}' is not a file", file.display()))?;
+    let temp = file.with_file_name(format!(".{}.apmtool-{}", name.to_string_lossy(), std::process::id()));
+    let result = fs::copy(file, &temp)
+        .context("Failed to copy the input file")
+        .and_then(|_| {
+            let mut out = Device::open(&temp, true)
+                .context("Failed to open the copy of the input file")?;
+            write(&mut out)?;
+            out.sync()
+                .context("Failed to update the copy of the input file")
+        })
+        .and_then(|_| fs::rename(&temp, file)
+            .context("Failed to replace the input file"));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Reads the partition map of an image or device, leaving the partitions on it until they are
+/// needed. Devices that are going to be written must not be in use.
+fn open_map(file: &Path, writable: bool) -> Result<ApmMap> {
+    if writable {
+        device::ensure_unused(file)?;
+        recover(file)?;
+    }
+    let device = Device::open(file, false)
+        .context("Failed to open the input file")?;
+    ApmMap::open(device)
+        .context("Failed parsing the input file as APM data")
+}
+
+/// Writes the blocks changed in `drive` back to the image it was read from
+fn save(file: &Path, drive: &mut ApmMap, backup: Option<&Path>) -> Result<()> {
+    write_safely(file, drive.map_blocks(), backup, |out| {
+        drive.flush(out)
+            .context("Failed to update the input file")
+    })
 }
 
 fn size_binary(v: &str) -> Result<u32, anyhow::Error> {
     Ok(parse_size::Config::new()
         .with_binary()
         .parse_size(v)
-        .map(|val| u32::try_from(val))??)
+        .map(u32::try_from)??)
+}
+
+/// Parses a size in bytes of up to 2 TiB into a number of blocks
+fn size_blocks(v: &str) -> Result<u32, anyhow::Error> {
+    let bytes = parse_size::Config::new()
+        .with_binary()
+        .parse_size(v)?;
+    Ok(u32::try_from(bytes.div_ceil(512))?)
+}
+
+fn parse_u32(v: &str) -> Result<u32, anyhow::Error> {
+    Ok(match v.strip_prefix("0x").or(v.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => v.parse()?,
+    })
+}
+
+/// Formats a type or creator code, replacing unprintable characters
+fn fourcc(code: &[u8; 4]) -> String {
+    macroman::decode(code)
+        .chars()
+        .map(|c| if c.is_control() { '.' } else { c })
+        .collect()
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let backup = cli.backup.as_deref();
 
     match cli.op {
         Cmd::Print{file, verbose} => {
-            let input = fs::read(&file)
-                .context("Failed to read the input file")?;
-            let drive = ApmMap::decode(input)
-                .context("Failed parsing the input file as APM data")?;
+            let drive = open_map(&file, false)?;
             println!("Block size: {} bytes", drive.block_size());
-            println!("Drive size: {} bytes", drive.blk_count() * 512);
+            println!("Drive size: {} bytes", drive.blk_count() as u64 * 512);
+            if drive.blk_count() != drive.device_blocks() {
+                println!("Warning: the device holds {} blocks, not {}", drive.device_blocks(), drive.blk_count());
+            }
             if verbose {
                 println!("Device type: {}", drive.dev_type());
                 println!("Device ID: {}", drive.dev_id());
                 println!("Reserved data: {}", drive.data());
             }
-            for (i, (d, _)) in drive.drivers().enumerate() {
+            for (i, d) in drive.drivers().enumerate() {
                 println!("Driver {}:", i);
                 println!("\tStart: {} blocks", d.start());
                 println!("\tSize: {} blocks", d.size());
-                println!("\tType: {}", d.ty());
+                println!("\tType: {}", d.driver_type());
             }
-            for (i, (p, _)) in drive.partitions().enumerate() {
+            for (i, p) in drive.partitions().enumerate() {
                 println!("Partition {}:", i);
                 println!("\tName: '{}'", p.name());
                 println!("\tType: '{}'", p.part_type());
                 println!("\tStart: {} blocks", p.start());
                 println!("\tLength: {} blocks", p.length());
+                let probe = drive.read_blocks(p.start(), p.length().min((detect::PROBE_LEN/512) as u32))
+                    .context("Failed to read the partition")?;
+                if let Some(fs) = detect::detect(&probe) {
+                    match &fs.name {
+                        Some(name) => println!("\tFilesystem: {} '{}', {} bytes", fs.fs, name, fs.size),
+                        None => println!("\tFilesystem: {}, {} bytes", fs.fs, fs.size),
+                    }
+                    for warning in fs.mismatches(p.part_type(), p.length() as u64*512) {
+                        println!("\tWarning: {}", warning);
+                    }
+                }
                 if verbose {
                     println!("\tData start: {} blocks", p.data_start());
                     println!("\tData length: {} blocks", p.data_size());
                     println!("\tStatus: 0x{:08x}", p.status());
                     println!("\tBoot code start: {} blocks", p.boot_start());
-                    println!("\tBoot code size: {} blocks", p.boot_size());
+                    println!("\tBoot code size: {} bytes", p.boot_size());
                     println!("\tBoot load address: 0x{:08x}", p.boot_load_address());
                     println!("\tBoot entry point: 0x{:08x}",  p.boot_entry());
                     println!("\tBoot code checksum: 0x{:08x}", p.boot_checksum());
@@ -112,66 +547,414 @@ fn main() -> Result<()> {
             }
         },
         Cmd::DumpPartition{file, num, path} => {
-            let input = fs::read(&file)
-                .context("Failed to read the input file")?;
-            let drive = ApmMap::decode(input)
-                .context("Failed parsing the input file as APM data")?;
-            let data = drive.partition_data(num as usize)
-                .ok_or(anyhow!("Failed to find partition"))?;
-            fs::write(&path, data)
+            let drive = open_map(&file, false)?;
+            let mut data = drive.partition_reader(num as usize)
+                .context("Failed to find partition")?;
+            let mut out = fs::File::create(&path)
+                .context("Failed to create the output file")?;
+            std::io::copy(&mut data, &mut out)
                 .context("Failed to write data of partition")?;
             },
-        Cmd::ReplacePartition{file, num, data} => {
+        Cmd::ReplacePartition{file, num, data, pad, truncate, grow} => {
             let data = fs::read(&data)
                 .context("Failed to read the input data file")?;
-            let input = fs::read(&file)
-                .context("Failed to read the input file")?;
-            let mut drive = ApmMap::decode(input)
-                .context("Failed parsing the input file as APM data")?;
-            drive.partition_data_mut(num as usize)
+            let mut drive = open_map(&file, true)?;
+            let mut fit = match (pad, truncate) {
+                (true, _) => Fit::Pad,
+                (false, true) => Fit::Truncate,
+                (false, false) => Fit::Exact,
+            };
+            let length = drive.partition_entry(num as usize)
                 .ok_or(anyhow!("Failed to find partition"))?
-                .copy_from_slice(&data);
-            fs::write(&file, drive.encode()?)
-                .context("Failed to update the input file")?;
+                .length();
+            let needed = (((data.len() + 0x1ff) & !0x1ff)/512) as u32;
+            let mut num = num as usize;
+            if grow && needed > length {
+                num = drive.resize_partition(num, needed)
+                    .context("Failed to grow the partition")?;
+                fit = Fit::Pad;
+            }
+            let kept = drive.partition_entry(num)
+                .map(|p| p.length() as usize*512)
+                .unwrap_or(0);
+            drive.write_partition_data(num, &data, fit)
+                .context("Failed to replace partition data, see --pad, --truncate and --grow")?;
+            save(&file, &mut drive, backup)?;
+            if fit == Fit::Truncate && data.len() > kept {
+                eprintln!("Warning: the last {} bytes of the data did not fit in the partition and were dropped", data.len() - kept);
+            }
         }
         Cmd::DumpDriver{file, num, path} => {
-            let input = fs::read(file)
-                .context("Failed to read the input file")?;
-            let drive = ApmMap::decode(input)
-                .context("Failed parsing the input file as APM data")?;
-            let data = drive.drivers()
-                .enumerate()
-                .find(|(p_num, _)| *p_num == num as usize)
-                .inspect(|(_, (info, _))| println!("Dumping {} blocks from {}", info.size(), info.start()))
-                .map(|(_, (_, d))| d)
+            let drive = open_map(&file, false)?;
+            let info = drive.drivers()
+                .nth(num as usize)
                 .ok_or(anyhow!("Unknown driver number {}", num))?;
+            println!("Dumping {} blocks from {}", info.size(), info.start());
+            let data = drive.driver_bytes(num as usize)
+                .context("Failed to read the driver")?;
             fs::write(&path, data)
                 .context("Failed to write data of partition")?;
         },
-        Cmd::Create{file, size, partition, driver, driver43} => {
-            let size = (size + 0x1ff & !0x1ff)/512;
+        Cmd::Create{file, size, partition, driver, driver_type, driver43, driver43_size, driver43_from, hfs_from_dir, volume_name, hfs_size, alloc} => {
+            let size = ((size + 0x1ff) & !0x1ff)/512;
             let mut drive = ApmMap::new(size);
             if let Some(p) = &driver43 {
-                let data = fs::read(p).unwrap();
-                drive.push_driver(1, &data[..(19*512)])?;
-                drive.push_partition_at("nochecksumplz", "Apple_Driver43", "68000", &data, 64)?;
+                let data = fs::read(p)
+                    .context("Failed to read driver data")?;
+                let code_len = driver43_size.context("The driver length is missing, see --driver43-size")?;
+                drive.install_driver43(&data, code_len as usize, alloc.policy())
+                    .context("Failed to install the driver")?;
             }
-            for d in driver {
-                let data = fs::read(&d)
+            if let Some(p) = &driver43_from {
+                let source = open_map(p, false)?;
+                let num = (0..source.drivers().count())
+                    .find(|&i| source.driver_partition(i)
+                        .and_then(|idx| source.partition_entry(idx))
+                        .is_some_and(|e| e.part_type() == "Apple_Driver43"))
+                    .ok_or(anyhow!("'{}' has no Apple_Driver43 driver", p.display()))?;
+                drive.copy_driver(&source, num, alloc.policy())
+                    .context("Failed to copy the driver")?;
+            }
+            if driver_type.len() > driver.len() {
+                bail!("More driver types than drivers given");
+            }
+            for (i, d) in driver.iter().enumerate() {
+                let data = fs::read(d)
                     .context("Failed to read driver data")?;
-                drive.push_driver(1, &data)
+                let ty = driver_type.get(i)
+                    .copied()
+                    .unwrap_or(DriverType::MacOs68k);
+                drive.push_driver(ty, &data, alloc.policy())
                     .context("Failed to add the driver to drive")?;
             }
             for d in partition {
                 let data = fs::read(&d)
                     .context("Failed to read partition data")?;
-                drive.push_partition("MacOS", "Apple_HFS", &data)
+                drive.push_partition("MacOS", "Apple_HFS", &data, alloc.policy())
                     .context("Failed to add the partition to drive")?;
             }
-            fs::write(&file, drive.encode().context("Failed encoding the drive")?)
+            if let Some(dir) = &hfs_from_dir {
+                let policy = alloc.policy();
+                let blocks = match hfs_size {
+                    Some(s) => s.div_ceil(512),
+                    None => drive.holes()
+                        .into_iter()
+                        .map(|(start, end)| end.saturating_sub(start.next_multiple_of(policy.align())))
+                        .max()
+                        .unwrap_or(0),
+                };
+                let mut root = hfs::folder_from_dir(dir)
+                    .context("Failed to read the directory")?;
+                if let Some(name) = volume_name {
+                    root.name = name;
+                }
+                let mut data = vec![0u8; blocks as usize * 512];
+                hfs::format(&mut data, &root.name.clone(), &root)
+                    .context("Failed to build the HFS volume")?;
+                drive.push_partition("MacOS", "Apple_HFS", &data, policy)
+                    .context("Failed to add the HFS partition to drive")?;
+            }
+            drive.encode()
+                .context("Failed encoding the drive")?;
+            fs::write(&file, drive.raw().expect("new drives are kept in memory"))
                 .context("Failed saving the output file")?;
             println!("{:#?}", drive);
         },
+        Cmd::AddPartition{file, name, ty, size, data, start, alloc} => {
+            let data = match &data {
+                Some(p) => fs::read(p)
+                    .context("Failed to read partition data")?,
+                None => Vec::new(),
+            };
+            let size = size.map(|s| s as usize).unwrap_or(data.len());
+            let blocks = (((size + 0x1ff) & !0x1ff)/512) as u32;
+            let mut drive = open_map(&file, true)?;
+            let start = match start {
+                Some(s) => s,
+                None => drive.find_hole(blocks, alloc.policy())
+                    .context("Failed to find space for the partition")?,
+            };
+            let entry = PartitionEntry::new()
+                .with_start(start)
+                .with_length(blocks)
+                .with_name(name)
+                .with_type(ty);
+            let idx = drive.insert_partition(entry, &data)
+                .context("Failed to add the partition to drive")?;
+            save(&file, &mut drive, backup)?;
+            println!("Added partition {} at block {} ({} blocks)", idx, start, blocks);
+        },
+        Cmd::AddDriver{file, data, ty, partition_type, proc_type, name, alloc} => {
+            let data = fs::read(&data)
+                .context("Failed to read driver data")?;
+            let mut drive = open_map(&file, true)?;
+            let entry = PartitionEntry::new()
+                .with_name(name)
+                .with_type(partition_type.as_deref().unwrap_or(ty.partition_type()))
+                .with_proc_type(proc_type.as_deref().unwrap_or(ty.proc_type()));
+            let num = drive.push_driver_partition(ty, entry, &data, data.len(), alloc.policy())
+                .context("Failed to add the driver to drive")?;
+            save(&file, &mut drive, backup)?;
+            println!("Added driver {}", num);
+        },
+        Cmd::ReplaceDriver{file, num, data} => {
+            let data = fs::read(&data)
+                .context("Failed to read driver data")?;
+            let mut drive = open_map(&file, true)?;
+            drive.replace_driver(num as usize, &data)
+                .context("Failed to replace the driver")?;
+            save(&file, &mut drive, backup)?;
+        },
+        Cmd::RemoveDriver{file, num} => {
+            let mut drive = open_map(&file, true)?;
+            drive.remove_driver(num as usize)
+                .context("Failed to remove the driver")?;
+            save(&file, &mut drive, backup)?;
+        },
+        Cmd::IdentifyDrivers{file, db} => {
+            let mut known = KnownDriver::builtin();
+            if let Some(p) = &db {
+                known.extend(KnownDriver::parse_db(&fs::read_to_string(p)
+                    .context("Failed to read the fingerprint database")?));
+            }
+            let drive = open_map(&file, false)?;
+            for ident in ident::identify_drivers(&drive, &known) {
+                match (ident.driver, ident.partition) {
+                    (Some(d), Some(p)) => println!("Driver {} in partition {}:", d, p),
+                    (Some(d), None) => println!("Driver {}:", d),
+                    (None, Some(p)) => println!("Partition {} (not in the driver map):", p),
+                    (None, None) => unreachable!(),
+                }
+                println!("\tIdentified as: {}", ident.matched);
+                if let Some(ty) = ident.system_type {
+                    println!("\tType: {}", ty);
+                }
+                if let Some(proc_type) = &ident.proc_type {
+                    println!("\tProcessor type: '{}'", proc_type);
+                }
+                println!("\tSize: {} bytes", ident.len);
+                println!("\tSHA-1: {}", ident.sha1);
+                match ident.checksum_ok {
+                    Some(true) => println!("\tChecksum: OK"),
+                    Some(false) => println!("\tChecksum: mismatch, the driver was modified"),
+                    None => (),
+                }
+                if ident.is_modified() {
+                    println!("\tWarning: the driver was modified");
+                } else if ident.matched == Match::Unknown {
+                    println!("\tWarning: unknown driver");
+                }
+            }
+        },
+        Cmd::RestoreMap{file, saved} => {
+            let saved = fs::read(&saved)
+                .context("Failed to read the saved partition map")?;
+            if saved.len() < 1024 || saved.len() % 512 != 0 || &saved[..2] != b"ER" || &saved[512..514] != b"PM" {
+                bail!("The saved partition map is damaged");
+            }
+            let size = Device::open(&file, false)
+                .context("Failed to open the input file")?
+                .size();
+            if (saved.len() as u64) > size {
+                bail!("The saved partition map is larger than the device");
+            }
+            write_safely(&file, (saved.len()/512) as u32, backup, |out| {
+                out.write_all(&saved)
+                    .context("Failed to write the partition map")
+            })?;
+        },
+        Cmd::Scan{file} => {
+            let device = Device::open(&file, false)
+                .context("Failed to open the input file")?;
+            let candidates = scan::scan(&device)
+                .context("Failed to scan the input file")?;
+            if candidates.is_empty() {
+                println!("No partitions found");
+            }
+            for c in candidates {
+                println!("Blocks {}..{} ({} blocks):", c.start, c.end(), c.length);
+                if let Some((block, entry)) = &c.entry {
+                    println!("\tMap entry in block {}: '{}' of type '{}'", block, entry.name(), entry.part_type());
+                }
+                if c.weak {
+                    println!("\tWarning: the map entry is inconsistent or reaches past the end of the disk");
+                }
+                if let Some(fs) = &c.filesystem {
+                    match &fs.name {
+                        Some(name) => println!("\tFilesystem: {} '{}', {} bytes", fs.fs, name, fs.size),
+                        None => println!("\tFilesystem: {}, {} bytes", fs.fs, fs.size),
+                    }
+                }
+            }
+        },
+        Cmd::RebuildMap{file, from_scan: _} => {
+            device::ensure_unused(&file)?;
+            recover(&file)?;
+            let device = Device::open(&file, false)
+                .context("Failed to open the input file")?;
+            let candidates = scan::scan(&device)
+                .context("Failed to scan the input file")?;
+            let mut drive = scan::rebuild(device, &candidates)
+                .context("Failed to build a new partition map")?;
+            save(&file, &mut drive, backup)?;
+            for (i, p) in drive.partitions().enumerate() {
+                println!("Partition {}: '{}' of type '{}', blocks {}..{}", i, p.name(), p.part_type(), p.start(), p.start() + p.length());
+            }
+        },
+        Cmd::Mkfs{file, num, hfs: _, name} => {
+            let mut drive = open_map(&file, true)?;
+            let entry = drive.partition_entry(num as usize)
+                .ok_or(anyhow!("Failed to find partition"))?;
+            if entry.part_type() != "Apple_HFS" {
+                eprintln!("Warning: partition type is '{}', not 'Apple_HFS'", entry.part_type());
+            }
+            let mut data = drive.partition_data(num as usize)
+                .context("Failed to read the partition")?
+                .into_owned();
+            hfs::format_blank(&mut data, &name)
+                .context("Failed to create the HFS volume")?;
+            drive.write_partition_data(num as usize, &data, Fit::Exact)?;
+            save(&file, &mut drive, backup)?;
+        },
+        Cmd::Ls{file, num, path} => {
+            let drive = open_map(&file, false)?;
+            let reader = drive.partition_reader(num as usize)
+                .context("Failed to read the partition")?;
+            let volume = hfs::Volume::open(reader)
+                .context("Failed to open the HFS volume")?;
+            let dir = volume.lookup(&path)
+                .context("Failed to find the directory")?;
+            let entries = match &dir.kind {
+                EntryKind::Directory(info) => volume.read_dir(info.id)?,
+                EntryKind::File(_) => vec![dir],
+            };
+            for e in entries {
+                match &e.kind {
+                    EntryKind::Directory(d) => println!("{:9} {:>10} {:>10}  {}/", "folder", d.valence, "", e.name),
+                    EntryKind::File(f) => println!("{} {} {:>10} {:>10}  {}",
+                        fourcc(&f.file_type), fourcc(&f.creator), f.data_size, f.rsrc_size, e.name),
+                }
+            }
+        },
+        Cmd::Get{file, num, path, dest, rsrc, format} => {
+            let drive = open_map(&file, false)?;
+            let reader = drive.partition_reader(num as usize)
+                .context("Failed to read the partition")?;
+            let mut volume = hfs::Volume::open(reader)
+                .context("Failed to open the HFS volume")?;
+            let entry = volume.lookup(&path)
+                .context("Failed to find the file")?;
+            let EntryKind::File(info) = &entry.kind else {
+                bail!("'{}' is a directory", path);
+            };
+            if let Some(format) = format {
+                let contents = volume.read_file(&entry)
+                    .context("Failed to read the file")?;
+                let (contents, sidecar) = ExportFormat::from(format).encode(&contents);
+                fs::write(&dest, contents)
+                    .context("Failed to save the file")?;
+                if let Some(sidecar) = sidecar {
+                    let name = dest.file_name()
+                        .ok_or(anyhow!("Destination has no file name"))?;
+                    fs::write(dest.with_file_name(format!("._{}", name.to_string_lossy())), sidecar)
+                        .context("Failed to save the AppleDouble file")?;
+                }
+            } else {
+                let fork = if rsrc { Fork::Resource } else { Fork::Data };
+                let contents = volume.read_fork(info, fork)
+                    .context("Failed to read the file")?;
+                fs::write(&dest, contents)
+                    .context("Failed to save the file")?;
+            }
+        },
+        Cmd::Extract{file, num, dest, path, format} => {
+            let drive = open_map(&file, false)?;
+            let reader = drive.partition_reader(num as usize)
+                .context("Failed to read the partition")?;
+            let mut volume = hfs::Volume::open(reader)
+                .context("Failed to open the HFS volume")?;
+            let dir = volume.lookup(&path)
+                .context("Failed to find the directory")?;
+            let EntryKind::Directory(info) = &dir.kind else {
+                bail!("'{}' is not a directory", path);
+            };
+            hfs::extract(&mut volume, info.id, &dest, format.into())
+                .context("Failed to extract the files")?;
+        },
+        Cmd::EditPartition{file, num, name, ty, processor, status} => {
+            let mut drive = open_map(&file, true)?;
+            let entry = drive.partition_entry_mut(num as usize)
+                .ok_or(anyhow!("Failed to find partition"))?;
+            if let Some(name) = name {
+                entry.set_name(name);
+            }
+            if let Some(ty) = ty {
+                entry.set_type(ty);
+            }
+            if let Some(processor) = processor {
+                entry.set_proc_type(processor);
+            }
+            if let Some(status) = status {
+                entry.set_status(status);
+            }
+            save(&file, &mut drive, backup)?;
+        },
+        Cmd::Compact{file} => {
+            let mut drive = open_map(&file, true)?;
+            let mut moves = Vec::new();
+            write_safely(&file, drive.map_blocks(), backup, |out| {
+                moves = drive.compact(out)
+                    .context("Failed to compact the partitions")?;
+                Ok(())
+            })?;
+            if moves.is_empty() {
+                println!("No partitions moved");
+            }
+            for (from, to) in moves {
+                println!("Moved partition from block {} to block {}", from, to);
+            }
+        },
+        Cmd::ResizeDisk{file, size, grow_last, grow_fs} => {
+            let mut drive = open_map(&file, true)?;
+            if is_device(&file) && size > drive.device_blocks() {
+                bail!("The device holds only {} blocks", drive.device_blocks());
+            }
+            let grown = drive.resize_device(size, grow_last)
+                .context("Failed to resize the drive")?;
+            let grown = grown.filter(|(num, old)| drive.partition_entry(*num).is_some_and(|p| p.length() > *old));
+            if let (true, Some((num, old))) = (grow_fs, grown) {
+                let unused = drive.grow_volume(num, old)
+                    .context("Failed to grow the volume")?;
+                if unused > 0 {
+                    eprintln!("Warning: the volume reached the largest size its format allows, the last {} bytes of the partition are unused", unused);
+                }
+            }
+            write_safely(&file, drive.map_blocks(), backup, |out| {
+                out.set_len(size as u64*512)
+                    .context("Failed to resize the input file")?;
+                drive.flush(out)
+                    .context("Failed to update the input file")
+            })?;
+        },
+        Cmd::ResizePartition{file, num, size, grow_fs} => {
+            let mut drive = open_map(&file, true)?;
+            let old = drive.partition_entry(num as usize)
+                .ok_or(anyhow!("Failed to find partition"))?
+                .length();
+            if grow_fs && size < old {
+                bail!("Volumes can only grow, not shrink");
+            }
+            let num = drive.resize_partition(num as usize, size)
+                .context("Failed to resize the partition")?;
+            if grow_fs {
+                let unused = drive.grow_volume(num, old)
+                    .context("Failed to grow the volume")?;
+                if unused > 0 {
+                    eprintln!("Warning: the volume reached the largest size its format allows, the last {} bytes of the partition are unused", unused);
+                }
+            }
+            save(&file, &mut drive, backup)?;
+        },
     }
 
     Ok(())